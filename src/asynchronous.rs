@@ -0,0 +1,351 @@
+use openssl::ssl::SslConnector;
+use serde_json::Value;
+use std::{
+    error::Error,
+    future::Future,
+    io::{self, ErrorKind},
+    iter,
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
+    task::JoinHandle,
+};
+use tokio_openssl::SslStream;
+
+use crate::{
+    certs::Certs,
+    communication::{
+        self, AckError, CallbackRegistry, CommunicationError, ErrorHandler, FrameDecoder, Pending,
+        Replay, SchemaSource,
+    },
+    connection::{self, Backoff, Connect, WappstoServers, WrappedSend},
+    fs_store::FsStore,
+    network::Network,
+    queue::MessageQueue,
+    rpc::RpcError,
+};
+
+///A network that talks to Wappsto from a task on a tokio runtime instead of threads of its own
+pub type AsyncNetwork = Network<AsyncConnection, FsStore, AsyncSend>;
+
+///Connection to Wappsto served by a single task on the tokio runtime it is started from. Unlike
+///[`Connection`](crate::connection::Connection), starting does not wait for the connection to be
+///established: failing to connect is reported to the error handler and retried with backoff.
+pub struct AsyncConnection {
+    certs: Certs,
+    url: &'static [&'static str],
+    backoff: Mutex<Backoff>,
+    session: Mutex<Option<AsyncSession>>,
+}
+
+impl Connect<AsyncSend> for AsyncConnection {
+    fn new(certs: Certs, server: WappstoServers) -> Self {
+        Self {
+            certs,
+            url: server.url(),
+            backoff: Mutex::new(Backoff::default()),
+            session: Mutex::new(None),
+        }
+    }
+
+    fn start(
+        &self,
+        callbacks: CallbackRegistry,
+        pending: Pending,
+        on_error: ErrorHandler,
+        queue: Arc<Mutex<MessageQueue>>,
+        schema: SchemaSource,
+    ) -> Result<AsyncSend, Box<dyn Error>> {
+        tokio::runtime::Handle::try_current()?;
+        let connector = connection::connector(&self.certs)?;
+        let previous = self.session.lock().unwrap().take().map(AsyncSession::stop);
+        let url = self.url;
+        let replay = Arc::new(Mutex::new(Replay::new()));
+        let session = supervise(
+            previous,
+            callbacks,
+            pending,
+            on_error,
+            move || open(connector.clone(), url),
+            *self.backoff.lock().unwrap(),
+            schema,
+            Arc::clone(&replay),
+            queue,
+        );
+        let send = session.sender();
+        self.session.lock().unwrap().replace(session);
+
+        Ok(AsyncSend::new(send, replay))
+    }
+
+    ///Ask the task to write everything sent so far and close the connection. The runtime cannot
+    ///be blocked on, so the task finishes in the background; starting again waits for it before
+    ///connecting.
+    fn stop(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(session) = self.session.lock().unwrap().take() {
+            session.stop();
+        }
+        Ok(())
+    }
+
+    fn set_backoff(&self, backoff: Backoff) {
+        *self.backoff.lock().unwrap() = backoff
+    }
+}
+
+async fn open(
+    connector: SslConnector,
+    url: &'static [&'static str],
+) -> io::Result<SslStream<TcpStream>> {
+    let (host, address) = connection::host(url);
+    let stream = TcpStream::connect(address).await?;
+    let ssl = connector
+        .configure()
+        .and_then(|c| c.into_ssl(&host))
+        .map_err(io::Error::other)?;
+    let mut stream = SslStream::new(ssl, stream).map_err(io::Error::other)?;
+    Pin::new(&mut stream)
+        .connect()
+        .await
+        .map_err(io::Error::other)?;
+    Ok(stream)
+}
+
+///The sending half of an [`AsyncConnection`]. Sending never blocks.
+pub struct AsyncSend {
+    send: UnboundedSender<String>,
+    replay: Arc<Mutex<Replay>>,
+}
+
+impl AsyncSend {
+    pub fn new(send: UnboundedSender<String>, replay: Arc<Mutex<Replay>>) -> Self {
+        Self { send, replay }
+    }
+}
+
+impl WrappedSend for AsyncSend {
+    fn send(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        self.replay.lock().unwrap().record(&msg);
+        self.send.send(msg)?;
+        Ok(())
+    }
+}
+
+///Handle to the task serving a connection to Wappsto
+pub struct AsyncSession {
+    send: UnboundedSender<String>,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl AsyncSession {
+    pub fn sender(&self) -> UnboundedSender<String> {
+        self.send.clone()
+    }
+
+    ///Ask the task to write every message sent so far and close the stream. The returned handle
+    ///completes once it has.
+    pub fn stop(self) -> JoinHandle<()> {
+        self.stop.send(true).ok();
+        self.task
+    }
+}
+
+///Like [`communication::supervise`], but serves the connection from a task spawned on the current
+///runtime. The first stream is opened with `connect` as well, and messages sent before it is
+///open are written once it is. Nothing is opened before the task of the `previous` session, if
+///any, has finished.
+#[allow(clippy::too_many_arguments)]
+pub fn supervise<T, F, Fut>(
+    previous: Option<JoinHandle<()>>,
+    callbacks: CallbackRegistry,
+    pending: Pending,
+    on_error: ErrorHandler,
+    connect: F,
+    backoff: Backoff,
+    schema: SchemaSource,
+    replay: Arc<Mutex<Replay>>,
+    queue: Arc<Mutex<MessageQueue>>,
+) -> AsyncSession
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = io::Result<T>> + Send,
+{
+    let (send, mut receive) = unbounded_channel::<String>();
+    let (stop, mut stopping) = watch::channel(false);
+    let task = tokio::spawn(async move {
+        if let Some(previous) = previous {
+            previous.await.ok();
+        }
+        let mut connected = false;
+        let mut attempt = 0;
+        loop {
+            match connect().await {
+                Ok(stream) => {
+                    let first = match connected {
+                        true => communication::reconnected(&schema, &replay, &queue, &callbacks),
+                        false => queue.lock().unwrap().drain(),
+                    };
+                    connected = true;
+                    attempt = 0;
+                    let ended = session(
+                        stream,
+                        first,
+                        &callbacks,
+                        &pending,
+                        &on_error,
+                        &mut receive,
+                        &mut stopping,
+                    )
+                    .await;
+                    if !communication::disconnected(ended, &queue, &on_error) {
+                        break;
+                    }
+                }
+                Err(e) => on_error.lock().unwrap()(CommunicationError::ReconnectFailed(e)),
+            }
+            let delay = backoff.delay(attempt);
+            if queue_until(delay, &mut receive, &queue, &mut stopping).await {
+                break;
+            }
+            attempt = attempt.saturating_add(1);
+        }
+    });
+    AsyncSession { send, stop, task }
+}
+
+///Move everything sent while disconnected into the queue until `delay` has passed, so the
+///overflow policy applies. Returns whether the session was stopped in the meantime.
+async fn queue_until(
+    delay: Duration,
+    receive: &mut UnboundedReceiver<String>,
+    queue: &Mutex<MessageQueue>,
+    stopping: &mut watch::Receiver<bool>,
+) -> bool {
+    let sleep = tokio::time::sleep(delay);
+    tokio::pin!(sleep);
+    loop {
+        tokio::select! {
+            _ = &mut sleep => return false,
+            _ = stopping.changed() => return true,
+            Some(msg) = receive.recv() => queue.lock().unwrap().push(msg),
+        }
+    }
+}
+
+///Read and write a single stream until it fails or the session is stopped. The messages in
+///`first` are written before anything queued on `receive`. If the stream fails, the messages that
+///were not written are returned along with the error.
+async fn session<T>(
+    stream: T,
+    first: Vec<String>,
+    callbacks: &CallbackRegistry,
+    pending: &Pending,
+    on_error: &ErrorHandler,
+    receive: &mut UnboundedReceiver<String>,
+    stopping: &mut watch::Receiver<bool>,
+) -> Result<(), (io::Error, Vec<String>)>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    let (mut read, mut write) = tokio::io::split(stream);
+    let mut first = first.into_iter();
+    while let Some(msg) = first.next() {
+        if let Err(e) = write.write_all(msg.as_bytes()).await {
+            return Err((e, iter::once(msg).chain(first).collect()));
+        }
+    }
+
+    let (respond, responses) = mpsc::channel();
+    let mut decoder = FrameDecoder::new();
+    let mut buf = [0; 4096];
+    loop {
+        tokio::select! {
+            _ = stopping.changed() => break,
+            msg = receive.recv() => match msg {
+                Some(msg) => {
+                    if let Err(e) = write.write_all(msg.as_bytes()).await {
+                        return Err((e, vec![msg]));
+                    }
+                }
+                None => break,
+            },
+            read = read.read(&mut buf) => {
+                let bytes = match read {
+                    Ok(0) => return Err((io::Error::from(ErrorKind::UnexpectedEof), vec![])),
+                    Ok(bytes) => bytes,
+                    Err(e) => return Err((e, vec![])),
+                };
+                decoder.push(&buf[..bytes]);
+                while let Some(frame) = decoder.next_frame() {
+                    if let Err(e) = communication::handle_frame(frame, callbacks, pending, &respond) {
+                        on_error.lock().unwrap()(e);
+                    }
+                }
+                let answers = responses.try_iter().collect::<Vec<String>>();
+                for msg in answers {
+                    write.write_all(msg.as_bytes()).await.map_err(|e| (e, vec![]))?;
+                }
+            }
+        }
+    }
+
+    while let Ok(msg) = receive.try_recv() {
+        if let Err(e) = write.write_all(msg.as_bytes()).await {
+            return Err((e, vec![msg]));
+        }
+    }
+    write.shutdown().await.ok();
+    Ok(())
+}
+
+///The response Wappsto will give to a request, for use from async code. Dropping it does not
+///affect the request.
+pub struct AsyncAck {
+    id: String,
+    deadline: Instant,
+    receive: oneshot::Receiver<Result<Value, RpcError>>,
+    pending: Pending,
+}
+
+impl AsyncAck {
+    ///Wait for the response to the request with the given id
+    pub fn new(pending: &Pending, id: &str) -> Self {
+        let (answer, receive) = oneshot::channel();
+        let deadline = pending.wait_for(
+            id,
+            Box::new(move |response| {
+                answer.send(response).ok();
+            }),
+        );
+        Self {
+            id: String::from(id),
+            deadline,
+            receive,
+            pending: pending.clone(),
+        }
+    }
+
+    ///Wait until Wappsto has answered the request, or until it times out
+    pub async fn wait(self) -> Result<Value, AckError> {
+        let response = tokio::time::timeout_at(self.deadline.into(), self.receive).await;
+        self.pending.forget(&self.id);
+        match response {
+            Ok(Ok(response)) => response.map_err(AckError::Rejected),
+            _ => Err(AckError::TimedOut),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
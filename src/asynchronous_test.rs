@@ -0,0 +1,279 @@
+mod session {
+    use std::{
+        collections::HashMap,
+        io::{self, ErrorKind},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        sync::oneshot,
+    };
+    use uuid::Uuid;
+
+    use crate::{
+        asynchronous::{self, AsyncAck, AsyncSession},
+        communication::{CallbackMap, Pending, Replay, RequestHandler},
+        queue::MessageQueue,
+        test_support::{control_state_rpc, fast_backoff, ignore_errors},
+    };
+
+    #[tokio::test]
+    async fn should_callback_on_control_and_respond() {
+        let id = Uuid::new_v4();
+        let controlled = Arc::new(Mutex::new(None));
+        let controlled_arc = Arc::clone(&controlled);
+        let mut callbacks: CallbackMap = HashMap::new();
+        callbacks.insert(
+            id,
+            RequestHandler::Control(Arc::new(Mutex::new(Arc::new(move |data| {
+                *controlled_arc.lock().unwrap() = Some(data);
+                Ok(())
+            })))),
+        );
+        let (mut server, _session) = connect(callbacks, Pending::default());
+
+        server
+            .write_all(control_state_rpc("1", id).as_bytes())
+            .await
+            .unwrap();
+
+        assert!(read(&mut server).await.contains("\"result\""));
+        assert_eq!(Some(String::from("1")), *controlled.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn should_pass_result_to_async_ack() {
+        let pending = Pending::default();
+        let ack = AsyncAck::new(&pending, "report");
+        let (mut server, _session) = connect(HashMap::new(), pending);
+
+        server
+            .write_all(br#"{"jsonrpc":"2.0","id":"report","result":{"value":true}}"#)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            serde_json::json!({"value": true}),
+            ack.wait().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_time_out_async_ack() {
+        let pending = Pending::new(Duration::from_millis(10));
+        let ack = AsyncAck::new(&pending, "report");
+
+        assert!(ack.wait().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_write_everything_sent_before_stop() {
+        let (mut server, session) = connect(HashMap::new(), Pending::default());
+
+        session.sender().send(String::from("first")).unwrap();
+        session.sender().send(String::from("second")).unwrap();
+        session.stop().await.unwrap();
+
+        let mut sent = String::new();
+        server.read_to_string(&mut sent).await.unwrap();
+        assert_eq!("firstsecond", sent);
+    }
+
+    #[tokio::test]
+    async fn should_retry_connecting() {
+        let attempts = Arc::new(Mutex::new(0));
+        let attempts_arc = Arc::clone(&attempts);
+        let (client, mut server) = tokio::io::duplex(4096);
+        let client = Arc::new(Mutex::new(Some(client)));
+        let session = asynchronous::supervise(
+            None,
+            Arc::default(),
+            Pending::default(),
+            ignore_errors(),
+            move || {
+                let attempt = {
+                    let mut attempts = attempts_arc.lock().unwrap();
+                    *attempts += 1;
+                    *attempts
+                };
+                let client = match attempt {
+                    1 => None,
+                    _ => client.lock().unwrap().take(),
+                };
+                async move { client.ok_or_else(|| io::Error::from(ErrorKind::ConnectionRefused)) }
+            },
+            fast_backoff(),
+            Box::new(|| None),
+            Arc::new(Mutex::new(Replay::new())),
+            Arc::new(Mutex::new(MessageQueue::default())),
+        );
+
+        session.sender().send(String::from("queued")).unwrap();
+
+        assert_eq!("queued", read(&mut server).await);
+        assert_eq!(2, *attempts.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn should_wait_for_previous_session_before_connecting() {
+        let (release, released) = oneshot::channel::<()>();
+        let released = Arc::new(Mutex::new(Some(released)));
+        let (client, mut server) = tokio::io::duplex(4096);
+        let client = Arc::new(Mutex::new(Some(client)));
+        let previous = asynchronous::supervise(
+            None,
+            Arc::default(),
+            Pending::default(),
+            ignore_errors(),
+            move || {
+                let released = released.lock().unwrap().take();
+                let client = client.lock().unwrap().take();
+                async move {
+                    if let Some(released) = released {
+                        released.await.ok();
+                    }
+                    client.ok_or_else(|| io::Error::from(ErrorKind::NotConnected))
+                }
+            },
+            fast_backoff(),
+            Box::new(|| None),
+            Arc::new(Mutex::new(Replay::new())),
+            Arc::new(Mutex::new(MessageQueue::default())),
+        );
+        previous.sender().send(String::from("previous")).unwrap();
+        let connected = Arc::new(Mutex::new(false));
+        let connected_arc = Arc::clone(&connected);
+        let _session = asynchronous::supervise(
+            Some(previous.stop()),
+            Arc::default(),
+            Pending::default(),
+            ignore_errors(),
+            move || {
+                *connected_arc.lock().unwrap() = true;
+                async { Err::<DuplexStream, _>(io::Error::from(ErrorKind::NotConnected)) }
+            },
+            fast_backoff(),
+            Box::new(|| None),
+            Arc::new(Mutex::new(Replay::new())),
+            Arc::new(Mutex::new(MessageQueue::default())),
+        );
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!*connected.lock().unwrap());
+        release.send(()).unwrap();
+
+        let mut sent = String::new();
+        server.read_to_string(&mut sent).await.unwrap();
+        assert_eq!("previous", sent);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(*connected.lock().unwrap());
+    }
+
+    fn connect(callbacks: CallbackMap, pending: Pending) -> (DuplexStream, AsyncSession) {
+        let (client, server) = tokio::io::duplex(4096);
+        let client = Arc::new(Mutex::new(Some(client)));
+        let session = asynchronous::supervise(
+            None,
+            Arc::new(Mutex::new(callbacks)),
+            pending,
+            ignore_errors(),
+            move || {
+                let client = client.lock().unwrap().take();
+                async move { client.ok_or_else(|| io::Error::from(ErrorKind::NotConnected)) }
+            },
+            fast_backoff(),
+            Box::new(|| None),
+            Arc::new(Mutex::new(Replay::new())),
+            Arc::new(Mutex::new(MessageQueue::default())),
+        );
+        (server, session)
+    }
+
+    async fn read(server: &mut DuplexStream) -> String {
+        let mut buf = [0; 4096];
+        let bytes = tokio::time::timeout(Duration::from_secs(1), server.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        String::from_utf8_lossy(&buf[..bytes]).to_string()
+    }
+}
+
+mod value {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tokio::sync::oneshot;
+
+    use crate::{
+        asynchronous::AsyncSend,
+        network::{InnerValue, Value, ValuePermission},
+        queue::MessageQueue,
+        schema::ValueType,
+    };
+
+    #[tokio::test]
+    async fn should_run_async_control_handler_on_runtime() {
+        let value: Value<AsyncSend> = Value::new(InnerValue::new(
+            "test_value",
+            ValuePermission::W(Box::new(|_| {})),
+            ValueType::default(),
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(MessageQueue::default())),
+        ));
+        let (controlled, received) = oneshot::channel();
+        let controlled = Mutex::new(Some(controlled));
+        value
+            .on_control_async(move |data: f64| {
+                let controlled = controlled.lock().unwrap().take();
+                async move {
+                    if let Some(controlled) = controlled {
+                        controlled.send(data).ok();
+                    }
+                }
+            })
+            .unwrap();
+
+        value
+            .inner
+            .lock()
+            .unwrap()
+            .control(String::from("1"))
+            .unwrap();
+
+        let data = tokio::time::timeout(Duration::from_secs(1), received)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(1f64, data);
+    }
+
+    #[test]
+    fn should_fail_to_handle_controls_async_outside_runtime() {
+        let value: Value<AsyncSend> = Value::new(InnerValue::new(
+            "test_value",
+            ValuePermission::W(Box::new(|_| {})),
+            ValueType::default(),
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(MessageQueue::default())),
+        ));
+        assert!(value.on_control_async(|_: f64| async {}).is_err());
+    }
+
+    #[test]
+    fn should_report_from_any_task() {
+        fn assert_send<T: Send>(_: T) {}
+        let value: Value<AsyncSend> = Value::new(InnerValue::new(
+            "test_value",
+            ValuePermission::R,
+            ValueType::default(),
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(MessageQueue::default())),
+        ));
+        assert_send(value.report_async(1f64));
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+///When changes to a network are saved to its store. Saving waits until no further changes have
+///been made for `delay`, but never postpones a change for longer than `max_delay`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Autosave {
+    pub delay: Duration,
+    pub max_delay: Duration,
+    ///Also save after every report, so the last reported data survives a crash. Off by default,
+    ///as a value reported often would be saved as often; the last report is still saved along
+    ///with any other change, and when the network is stopped.
+    pub save_reports: bool,
+}
+
+impl Default for Autosave {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            save_reports: false,
+        }
+    }
+}
+
+///Lets the autosave thread of a network know that it has changed. Does nothing while autosave is
+///turned off.
+#[derive(Clone, Default)]
+pub(crate) struct Changes {
+    notify: Arc<Mutex<Option<Sender<()>>>>,
+    save_reports: Arc<AtomicBool>,
+}
+
+impl Changes {
+    pub fn notify(&self) {
+        if let Some(notify) = self.notify.lock().unwrap().as_ref() {
+            notify.send(()).ok();
+        }
+    }
+
+    ///Like [`notify`](Self::notify), but only if autosave is configured to save reports
+    pub fn notify_report(&self) {
+        if self.save_reports.load(Ordering::SeqCst) {
+            self.notify()
+        }
+    }
+
+    ///Call `save` on a thread of its own after changes, as configured by `autosave`. The thread
+    ///stops once `save` returns false, or once autosave is turned off, after saving any changes
+    ///it was waiting on. Replaces the thread started by any earlier call.
+    pub fn autosave<F>(&self, autosave: Autosave, mut save: F)
+    where
+        F: FnMut() -> bool + Send + 'static,
+    {
+        let (notify, changed) = channel();
+        self.save_reports
+            .store(autosave.save_reports, Ordering::SeqCst);
+        self.notify.lock().unwrap().replace(notify);
+        thread::spawn(move || {
+            while changed.recv().is_ok() {
+                let latest = Instant::now() + autosave.max_delay;
+                let mut deadline = (Instant::now() + autosave.delay).min(latest);
+                let stopped = loop {
+                    match changed.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(()) => deadline = (Instant::now() + autosave.delay).min(latest),
+                        Err(RecvTimeoutError::Timeout) => break false,
+                        Err(RecvTimeoutError::Disconnected) => break true,
+                    }
+                };
+                if !save() || stopped {
+                    return;
+                }
+            }
+        });
+    }
+
+    pub fn stop(&self) {
+        self.notify.lock().unwrap().take();
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread::sleep,
+    time::Duration,
+};
+
+use crate::autosave::{Autosave, Changes};
+
+#[test]
+fn should_save_once_changes_settle() {
+    let (changes, saves) = counting(Duration::from_millis(30), Duration::from_secs(10));
+
+    for _ in 0..5 {
+        changes.notify();
+        sleep(Duration::from_millis(5));
+    }
+    assert_eq!(0, *saves.lock().unwrap());
+    sleep(Duration::from_millis(150));
+
+    assert_eq!(1, *saves.lock().unwrap());
+}
+
+#[test]
+fn should_not_postpone_saving_past_max_delay() {
+    let (changes, saves) = counting(Duration::from_millis(50), Duration::from_millis(100));
+
+    for _ in 0..30 {
+        changes.notify();
+        sleep(Duration::from_millis(10));
+    }
+
+    assert!(*saves.lock().unwrap() >= 1);
+}
+
+#[test]
+fn should_save_waiting_changes_when_stopped() {
+    let (changes, saves) = counting(Duration::from_secs(10), Duration::from_secs(10));
+
+    changes.notify();
+    changes.stop();
+    sleep(Duration::from_millis(50));
+
+    assert_eq!(1, *saves.lock().unwrap());
+}
+
+#[test]
+fn should_not_save_without_changes() {
+    let (_changes, saves) = counting(Duration::from_millis(1), Duration::from_millis(1));
+
+    sleep(Duration::from_millis(50));
+
+    assert_eq!(0, *saves.lock().unwrap());
+}
+
+fn counting(delay: Duration, max_delay: Duration) -> (Changes, Arc<Mutex<u32>>) {
+    let changes = Changes::default();
+    let saves = Arc::new(Mutex::new(0));
+    let saves_counted = Arc::clone(&saves);
+    let autosave = Autosave {
+        delay,
+        max_delay,
+        ..Autosave::default()
+    };
+    changes.autosave(autosave, move || {
+        *saves_counted.lock().unwrap() += 1;
+        true
+    });
+    (changes, saves)
+}
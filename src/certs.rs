@@ -7,6 +7,7 @@ use std::error::Error;
 use uuid::Uuid;
 use x509_parser::pem::parse_x509_pem;
 
+#[derive(Clone)]
 pub struct Certs {
     pub id: Uuid,
     pub ca: X509,
@@ -23,15 +24,14 @@ impl Certs {
                 .subject()
                 .iter_common_name()
                 .next()
-                .unwrap()
+                .ok_or("The certificate has no common name")?
                 .as_str()?,
         )?;
         Ok(Self {
             id,
-            ca: X509::from_pem(ca.as_bytes()).unwrap(),
-            certificate: X509::from_pem(certificate_raw).unwrap(),
-            private_key: PKey::from_rsa(Rsa::private_key_from_pem(private_key.as_bytes()).unwrap())
-                .unwrap(),
+            ca: X509::from_pem(ca.as_bytes())?,
+            certificate: X509::from_pem(certificate_raw)?,
+            private_key: PKey::from_rsa(Rsa::private_key_from_pem(private_key.as_bytes())?)?,
         })
     }
 }
@@ -1,92 +1,902 @@
-use serde_json::{Map, Value};
+use serde::Serialize;
+use serde_json::{Deserializer, Map, Value};
 use std::{
-    collections::HashMap,
-    io::{ErrorKind, Read, Write},
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io::{self, ErrorKind, Read, Write},
+    iter,
     sync::{
-        mpsc::{self, Receiver, Sender},
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
         Arc, Mutex,
     },
-    thread,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 use uuid::Uuid;
 
-use crate::rpc::{RpcData, RpcRequest, RpcResponse};
+use crate::{
+    connection::Backoff,
+    queue::{reports, MessageQueue},
+    rpc::{RpcData, RpcError, RpcErrorResponse, RpcRequest, RpcResponse},
+    schema::ValidationError,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+///Handles the data of a control state. Data the handler rejects is answered with an error.
+pub type ControlHandler =
+    Arc<Mutex<Arc<dyn Fn(String) -> Result<(), ValidationError> + Send + Sync>>>;
+///Reports a fresh reading of a report state when Wappsto asks for one
+pub type RefreshHandler = Arc<Mutex<Arc<dyn Fn() -> Result<(), Box<dyn Error>> + Send + Sync>>>;
+///Called when Wappsto deletes a device or value
+pub type DeleteHandler = Arc<Mutex<Arc<dyn Fn() + Send + Sync>>>;
+pub type CallbackMap = HashMap<Uuid, RequestHandler>;
+///The handlers of a running connection, shared so they can be changed while it runs
+pub type CallbackRegistry = Arc<Mutex<CallbackMap>>;
+
+///What to do with a request for a state, value or device, keyed by its id
+#[derive(Clone)]
+pub enum RequestHandler {
+    Control(ControlHandler),
+    Refresh(RefreshHandler),
+    Delete(DeleteHandler),
+}
+
+pub type ErrorHandler = Arc<Mutex<Box<dyn Fn(CommunicationError) + Send + Sync>>>;
+
+///The message that publishes the network as it is at the time of the call, to publish it again
+///after a reconnect. None if there is no network to publish any more.
+pub type SchemaSource = Box<dyn Fn() -> Option<String> + Send>;
+
+///Requests sent to Wappsto that are waiting for a response, keyed by their id. Requests that are
+///not answered within the timeout are given up on, whether or not they were ever written to the
+///connection.
+#[derive(Clone)]
+pub struct Pending {
+    inner: Arc<Mutex<InnerPending>>,
+}
+
+struct InnerPending {
+    timeout: Duration,
+    requests: HashMap<String, Waiting>,
+}
+
+struct Waiting {
+    deadline: Instant,
+    answer: Answer,
+}
+
+type Answer = Box<dyn FnOnce(Result<Value, RpcError>) + Send>;
+
+impl Pending {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(InnerPending {
+                timeout,
+                requests: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.inner.lock().unwrap().timeout = timeout
+    }
+
+    ///Wait for the response to the request with the given id
+    pub fn track(&self, id: &str) -> Ack {
+        let (answer, receive) = mpsc::channel();
+        let deadline = self.wait_for(
+            id,
+            Box::new(move |response| {
+                answer.send(response).ok();
+            }),
+        );
+        Ack {
+            id: String::from(id),
+            deadline,
+            receive,
+            pending: self.clone(),
+        }
+    }
+
+    ///Call `answer` with the response to the request with the given id. Returns when the request
+    ///times out.
+    pub(crate) fn wait_for(&self, id: &str, answer: Answer) -> Instant {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner.requests.retain(|_, w| w.deadline > now);
+        let deadline = now + inner.timeout;
+        inner
+            .requests
+            .insert(String::from(id), Waiting { deadline, answer });
+        deadline
+    }
 
-pub type CallbackMap = HashMap<Uuid, Arc<Mutex<Box<dyn Fn(String) + Send + Sync>>>>;
+    fn is_waiting(&self, id: &str) -> bool {
+        self.inner.lock().unwrap().requests.contains_key(id)
+    }
+
+    ///Pass a response on to whoever is waiting for it
+    fn answer(&self, id: &str, response: Result<Value, RpcError>) {
+        let waiting = self.inner.lock().unwrap().requests.remove(id);
+        if let Some(waiting) = waiting {
+            (waiting.answer)(response)
+        }
+    }
+
+    pub(crate) fn forget(&self, id: &str) {
+        self.inner.lock().unwrap().requests.remove(id);
+    }
+}
+
+impl Default for Pending {
+    fn default() -> Self {
+        Self::new(REQUEST_TIMEOUT)
+    }
+}
+
+///The response Wappsto will give to a request. Dropping it does not affect the request.
+pub struct Ack {
+    id: String,
+    deadline: Instant,
+    receive: Receiver<Result<Value, RpcError>>,
+    pending: Pending,
+}
+
+impl Ack {
+    ///Block until Wappsto has answered the request, or until it times out
+    pub fn wait(self) -> Result<Value, AckError> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        let response = self.receive.recv_timeout(remaining);
+        self.pending.forget(&self.id);
+        match response {
+            Ok(response) => response.map_err(AckError::Rejected),
+            Err(_) => Err(AckError::TimedOut),
+        }
+    }
+
+    ///The answer to the request, or `None` while it is still waiting for one
+    pub fn poll(&self) -> Option<Result<Value, AckError>> {
+        match self.receive.try_recv() {
+            Ok(response) => Some(response.map_err(AckError::Rejected)),
+            Err(_) if Instant::now() >= self.deadline => {
+                self.pending.forget(&self.id);
+                Some(Err(AckError::TimedOut))
+            }
+            Err(_) => None,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+///Why a request was not acknowledged by Wappsto
+#[derive(Debug)]
+pub enum AckError {
+    ///Wappsto answered with an error
+    Rejected(RpcError),
+    ///No answer arrived in time
+    TimedOut,
+}
 
-pub fn start<T>(callbacks: CallbackMap, stream: T) -> Sender<String>
+impl Display for AckError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rejected(e) => write!(f, "Rejected by Wappsto: {}", e),
+            Self::TimedOut => write!(f, "Timed out waiting for Wappsto"),
+        }
+    }
+}
+
+impl Error for AckError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Rejected(e) => Some(e),
+            Self::TimedOut => None,
+        }
+    }
+}
+
+pub fn start<T>(
+    callbacks: CallbackRegistry,
+    pending: Pending,
+    on_error: ErrorHandler,
+    stream: T,
+) -> Session
 where
-    T: Read + Write + Send + 'static,
+    T: Read + Write + Close + Send + 'static,
 {
-    let stream = Arc::new(Mutex::new(stream));
-    let write = Arc::clone(&stream);
-    let read = Arc::clone(&stream);
     let (send, receive): (Sender<String>, Receiver<String>) = mpsc::channel();
     let send_from_reader = send.clone();
-    thread::spawn(move || write_thread(write, receive));
+    let stopping = Arc::new(AtomicBool::new(false));
+    let stop = Arc::clone(&stopping);
+    let thread = thread::spawn(move || {
+        if let Err((e, _)) = session(
+            callbacks,
+            pending,
+            Arc::clone(&on_error),
+            stream,
+            vec![],
+            &receive,
+            send_from_reader,
+            &stop,
+        ) {
+            on_error.lock().unwrap()(CommunicationError::Disconnected(e))
+        }
+    });
+    Session::new(send, stopping, thread)
+}
 
-    thread::spawn(move || {
-        read_thread(callbacks, read, send_from_reader);
+///Like [start], but keeps the connection alive: whenever the stream fails, a new one is opened
+///with `connect`, retrying according to `backoff`. Messages sent while disconnected are held in
+///`queue`, and are written after the schema from `schema` once a new stream is open, followed by
+///the reports held by `replay`.
+#[allow(clippy::too_many_arguments)]
+pub fn supervise<T, F>(
+    callbacks: CallbackRegistry,
+    pending: Pending,
+    on_error: ErrorHandler,
+    stream: T,
+    connect: F,
+    backoff: Backoff,
+    schema: SchemaSource,
+    replay: Arc<Mutex<Replay>>,
+    queue: Arc<Mutex<MessageQueue>>,
+) -> Session
+where
+    T: Read + Write + Close + Send + 'static,
+    F: Fn() -> io::Result<T> + Send + 'static,
+{
+    let (send, receive): (Sender<String>, Receiver<String>) = mpsc::channel();
+    let send_from_reader = send.clone();
+    let stopping = Arc::new(AtomicBool::new(false));
+    let stop = Arc::clone(&stopping);
+    let thread = thread::spawn(move || {
+        let mut stream = stream;
+        let mut first = vec![];
+        loop {
+            let ended = session(
+                Arc::clone(&callbacks),
+                pending.clone(),
+                Arc::clone(&on_error),
+                stream,
+                first,
+                &receive,
+                send_from_reader.clone(),
+                &stop,
+            );
+            if !disconnected(ended, &queue, &on_error) {
+                break;
+            }
+            stream = match reconnect(&connect, &backoff, &on_error, &receive, &queue, &stop) {
+                Some(stream) => stream,
+                None => break,
+            };
+            first = reconnected(&schema, &replay, &queue, &callbacks);
+        }
     });
-    send
+    Session::new(send, stopping, thread)
+}
+
+///Handle to the threads serving a connection to Wappsto
+pub struct Session {
+    send: Sender<String>,
+    stopping: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
 }
 
-fn read_thread<T>(mut callbacks: CallbackMap, read: Arc<Mutex<T>>, send: Sender<String>)
+impl Session {
+    fn new(send: Sender<String>, stopping: Arc<AtomicBool>, thread: JoinHandle<()>) -> Self {
+        Self {
+            send,
+            stopping,
+            thread,
+        }
+    }
+
+    pub fn sender(&self) -> Sender<String> {
+        self.send.clone()
+    }
+
+    ///Write every message sent so far, close the stream and wait for the reader and writer to
+    ///finish.
+    pub fn stop(self) -> Result<(), Box<dyn Error>> {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.thread
+            .join()
+            .map_err(|_| "Communication thread panicked".into())
+    }
+}
+
+///A stream that can be shut down gracefully, e.g. by sending a TLS close_notify
+pub trait Close {
+    fn close(&mut self) -> io::Result<()>;
+}
+
+///Whether a stream ended because the connection was lost rather than stopped. The messages it did
+///not write are queued again, to be written once a new stream is open.
+pub(crate) fn disconnected(
+    ended: Result<(), (io::Error, Vec<String>)>,
+    queue: &Mutex<MessageQueue>,
+    on_error: &ErrorHandler,
+) -> bool {
+    match ended {
+        Ok(()) => false,
+        Err((e, unsent)) => {
+            queue.lock().unwrap().requeue(unsent);
+            on_error.lock().unwrap()(CommunicationError::Disconnected(e));
+            true
+        }
+    }
+}
+
+///The messages to write first on a stream opened after the connection was lost: the network as
+///`schema` describes it by now, what was queued in the meantime, and the reports held by `replay`
+pub(crate) fn reconnected(
+    schema: &SchemaSource,
+    replay: &Mutex<Replay>,
+    queue: &Mutex<MessageQueue>,
+    callbacks: &CallbackRegistry,
+) -> Vec<String> {
+    let queued = queue.lock().unwrap().drain();
+    let schema = schema();
+    replay
+        .lock()
+        .unwrap()
+        .resume(schema, queued, &callbacks.lock().unwrap())
+}
+
+///Wait for the next attempt to reconnect, or `None` if the session is stopped in the meantime
+fn reconnect<T, F>(
+    connect: &F,
+    backoff: &Backoff,
+    on_error: &ErrorHandler,
+    receive: &Receiver<String>,
+    queue: &Mutex<MessageQueue>,
+    stopping: &AtomicBool,
+) -> Option<T>
 where
-    T: Read + Write + Send + 'static,
+    F: Fn() -> io::Result<T>,
 {
+    let mut attempt = 0;
     loop {
-        let mut buf = [0; 4096];
-        let bytes = read_all_from(&read, &mut buf);
-        let data: Result<Map<String, Value>, _> = serde_json::from_slice(&buf[..bytes]);
-
-        match data {
-            Ok(d) if d.get("method").is_some() => {
-                let data: RpcRequest = serde_json::from_slice(&buf[..bytes]).unwrap();
-                send.send(serde_json::to_string(&RpcResponse::new(data.id.clone(), true)).unwrap())
-                    .unwrap();
-                #[allow(clippy::single_match)]
-                match data.params.data {
-                    RpcData::Data(d) => {
-                        callbacks.get_mut(&d.meta.id).unwrap().lock().unwrap()(d.data)
-                    }
-                    _ => (),
-                };
+        queue_until(
+            Instant::now() + backoff.delay(attempt),
+            receive,
+            queue,
+            stopping,
+        );
+        if stopping.load(Ordering::SeqCst) {
+            break None;
+        }
+        match connect() {
+            Ok(stream) => break Some(stream),
+            Err(e) => on_error.lock().unwrap()(CommunicationError::ReconnectFailed(e)),
+        }
+        attempt = attempt.saturating_add(1);
+    }
+}
+
+///Move everything sent while disconnected into the queue, so the overflow policy applies
+fn queue_until(
+    deadline: Instant,
+    receive: &Receiver<String>,
+    queue: &Mutex<MessageQueue>,
+    stopping: &AtomicBool,
+) {
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        match receive.recv_timeout(remaining.min(POLL_INTERVAL)) {
+            Ok(msg) => queue.lock().unwrap().push(msg),
+            Err(RecvTimeoutError::Timeout) if stopping.load(Ordering::SeqCst) => break,
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(remaining),
+        }
+    }
+}
+
+///Run the reader and writer on a single stream until it fails or the session is stopped. The
+///messages in `first` are written before anything queued on `receive`. If the stream fails, the
+///messages that were not written are returned along with the error.
+#[allow(clippy::too_many_arguments)]
+fn session<T>(
+    callbacks: CallbackRegistry,
+    pending: Pending,
+    on_error: ErrorHandler,
+    stream: T,
+    first: Vec<String>,
+    receive: &Receiver<String>,
+    send: Sender<String>,
+    stopping: &AtomicBool,
+) -> Result<(), (io::Error, Vec<String>)>
+where
+    T: Read + Write + Close + Send + 'static,
+{
+    let stream = Arc::new(Mutex::new(stream));
+    let connected = Arc::new(AtomicBool::new(true));
+    let reader = {
+        let read = Arc::clone(&stream);
+        let connected = Arc::clone(&connected);
+        thread::spawn(move || read_thread(callbacks, pending, on_error, read, send, connected))
+    };
+
+    let written = write_thread(&stream, first, receive, &connected, stopping);
+    let stopped = connected.swap(false, Ordering::SeqCst) && written.is_ok();
+    let read = reader
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("Reader panicked")));
+    if stopped {
+        stream.lock().unwrap().close().ok();
+    }
+    written.and(read.map_err(|e| (e, vec![])))
+}
+
+fn read_thread<T>(
+    callbacks: CallbackRegistry,
+    pending: Pending,
+    on_error: ErrorHandler,
+    read: Arc<Mutex<T>>,
+    send: Sender<String>,
+    connected: Arc<AtomicBool>,
+) -> io::Result<()>
+where
+    T: Read + Write + Send + 'static,
+{
+    let mut decoder = FrameDecoder::new();
+    let mut buf = [0; 4096];
+    let result = loop {
+        let bytes = match read_all_from(&read, &mut buf, &connected) {
+            Ok(Some(0)) => break Err(io::Error::from(ErrorKind::UnexpectedEof)),
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        decoder.push(&buf[..bytes]);
+        while let Some(frame) = decoder.next_frame() {
+            if let Err(e) = handle_frame(frame, &callbacks, &pending, &send) {
+                on_error.lock().unwrap()(e);
             }
-            Ok(d) if d.get("result").is_some() => (),
-            Ok(d) => println!("Unknown message: {:?}", d),
-            Err(e) => panic!("Deserialize error: {}", e),
         }
+    };
+    connected.store(false, Ordering::SeqCst);
+    result
+}
+
+pub(crate) fn handle_frame(
+    frame: Result<Value, serde_json::Error>,
+    callbacks: &CallbackRegistry,
+    pending: &Pending,
+    send: &Sender<String>,
+) -> Result<(), CommunicationError> {
+    let data = match frame {
+        Ok(Value::Object(d)) => d,
+        Ok(Value::Array(batch)) => return handle_batch(batch, callbacks, pending, send),
+        Ok(d) => return Err(CommunicationError::UnknownMessage(d)),
+        Err(e) => {
+            respond(send, &RpcErrorResponse::parse_error());
+            return Err(CommunicationError::Deserialize(e));
+        }
+    };
+
+    match data {
+        d if d.get("method").is_some() => handle_request(d, callbacks, send),
+        d if d.get("result").is_some() || d.get("error").is_some() => handle_response(d, pending),
+        d => Err(CommunicationError::UnknownMessage(Value::Object(d))),
     }
 }
-fn read_all_from<T: Read>(reader: &Arc<Mutex<T>>, mut buf: &mut [u8]) -> usize {
-    loop {
-        let read = reader.lock().unwrap().read(&mut buf);
+
+///Handle each message of a batch, e.g. the responses to a batch of reports. Only the first error
+///is returned, but every message is handled.
+fn handle_batch(
+    batch: Vec<Value>,
+    callbacks: &CallbackRegistry,
+    pending: &Pending,
+    send: &Sender<String>,
+) -> Result<(), CommunicationError> {
+    batch
+        .into_iter()
+        .map(|data| handle_frame(Ok(data), callbacks, pending, send))
+        .fold(Ok(()), Result::and)
+}
+
+///Pass a response on to the request it answers. Results nobody waits for are dropped, but errors
+///are reported to the error handler.
+fn handle_response(
+    mut data: Map<String, Value>,
+    pending: &Pending,
+) -> Result<(), CommunicationError> {
+    let id = data.get("id").and_then(Value::as_str).map(String::from);
+    let response = match (data.remove("result"), data.get("error")) {
+        (Some(result), _) => Ok(result),
+        (None, Some(error)) => match serde_json::from_value::<RpcError>(error.clone()) {
+            Ok(error) => Err(error),
+            Err(_) => return Err(CommunicationError::UnknownMessage(Value::Object(data))),
+        },
+        (None, None) => return Err(CommunicationError::UnknownMessage(Value::Object(data))),
+    };
+    match (id, response) {
+        (Some(id), response) if pending.is_waiting(&id) => {
+            pending.answer(&id, response);
+            Ok(())
+        }
+        (_, Ok(_)) => Ok(()),
+        (id, Err(e)) => Err(CommunicationError::Rejected(id, e)),
+    }
+}
+
+fn handle_request(
+    data: Map<String, Value>,
+    callbacks: &CallbackRegistry,
+    send: &Sender<String>,
+) -> Result<(), CommunicationError> {
+    let id = data.get("id").and_then(Value::as_str).map(String::from);
+    match data.get("method").and_then(Value::as_str) {
+        Some("GET") => return handle_refresh(&data, id, callbacks, send),
+        Some("DELETE") => return handle_delete(&data, id, callbacks, send),
+        _ => {}
+    }
+    let request: RpcRequest = match serde_json::from_value(Value::Object(data)) {
+        Ok(r) => r,
+        Err(e) => {
+            respond(send, &RpcErrorResponse::invalid_request(id));
+            return Err(CommunicationError::InvalidRequest(e));
+        }
+    };
+
+    match request.params.data {
+        RpcData::Data(d) => match handler(callbacks, d.meta.id) {
+            Some(RequestHandler::Control(callback)) => match current(&callback)(d.data) {
+                Ok(()) => {
+                    respond(send, &RpcResponse::new(request.id, true));
+                    Ok(())
+                }
+                Err(e) => {
+                    respond(
+                        send,
+                        &RpcErrorResponse::invalid_params(request.id, &e.to_string()),
+                    );
+                    Err(CommunicationError::InvalidControl(d.meta.id, e))
+                }
+            },
+            _ => {
+                respond(
+                    send,
+                    &RpcErrorResponse::invalid_params(request.id, "Unknown state"),
+                );
+                Err(CommunicationError::UnknownState(d.meta.id))
+            }
+        },
+        _ => {
+            respond(send, &RpcResponse::new(request.id, true));
+            Ok(())
+        }
+    }
+}
+
+///The handler registered for `id`. The registry is not kept locked while the handler runs, so
+///handlers may change it.
+fn handler(callbacks: &CallbackRegistry, id: Uuid) -> Option<RequestHandler> {
+    callbacks.lock().unwrap().get(&id).cloned()
+}
+
+///The function a handler holds right now. It is not kept locked while it runs, so it may replace
+///itself, and replacing it never waits on code that runs in it.
+fn current<F: ?Sized>(handler: &Mutex<Arc<F>>) -> Arc<F> {
+    Arc::clone(&handler.lock().unwrap())
+}
+
+///The id of the entity a request is for: the id in the data of the request, or else the last
+///segment of its url
+fn target(data: &Map<String, Value>) -> Option<Uuid> {
+    let params = data.get("params");
+    params
+        .and_then(|p| p.pointer("/data/meta/id"))
+        .and_then(Value::as_str)
+        .or_else(|| {
+            params
+                .and_then(|p| p.get("url"))
+                .and_then(Value::as_str)
+                .and_then(|url| url.trim_end_matches('/').rsplit('/').next())
+        })
+        .and_then(|target| Uuid::parse_str(target).ok())
+}
+
+///Answer a GET on a report state by reporting a fresh reading
+fn handle_refresh(
+    data: &Map<String, Value>,
+    id: Option<String>,
+    callbacks: &CallbackRegistry,
+    send: &Sender<String>,
+) -> Result<(), CommunicationError> {
+    let (id, state) = match (id, target(data)) {
+        (Some(id), Some(state)) => (id, state),
+        (id, _) => {
+            respond(send, &RpcErrorResponse::invalid_request(id));
+            return Err(CommunicationError::UnknownMessage(Value::Object(
+                data.clone(),
+            )));
+        }
+    };
+    match handler(callbacks, state) {
+        Some(RequestHandler::Refresh(refresh)) => match current(&refresh)() {
+            Ok(()) => {
+                respond(send, &RpcResponse::new(id, true));
+                Ok(())
+            }
+            Err(e) => {
+                respond(send, &RpcErrorResponse::invalid_params(id, &e.to_string()));
+                Err(CommunicationError::RefreshFailed(state, e.to_string()))
+            }
+        },
+        _ => {
+            respond(send, &RpcErrorResponse::invalid_params(id, "Unknown state"));
+            Err(CommunicationError::UnknownState(state))
+        }
+    }
+}
+
+///Acknowledge a DELETE, and let the owner of a known device or value know it is gone. Deleting
+///anything else, e.g. the network itself, is only acknowledged.
+fn handle_delete(
+    data: &Map<String, Value>,
+    id: Option<String>,
+    callbacks: &CallbackRegistry,
+    send: &Sender<String>,
+) -> Result<(), CommunicationError> {
+    let id = match id {
+        Some(id) => id,
+        None => {
+            respond(send, &RpcErrorResponse::invalid_request(None));
+            return Err(CommunicationError::UnknownMessage(Value::Object(
+                data.clone(),
+            )));
+        }
+    };
+    respond(send, &RpcResponse::new(id, true));
+    if let Some(RequestHandler::Delete(delete)) = target(data).and_then(|t| handler(callbacks, t)) {
+        current(&delete)()
+    }
+    Ok(())
+}
+
+fn respond<R: Serialize>(send: &Sender<String>, response: &R) {
+    if let Ok(response) = serde_json::to_string(response) {
+        send.send(response).ok();
+    }
+}
+
+///Errors encountered while receiving messages from Wappsto. None of these are fatal; the reader
+///keeps running and reports them through the error handler registered on the network.
+#[derive(Debug)]
+pub enum CommunicationError {
+    ///The incoming bytes were not valid JSON
+    Deserialize(serde_json::Error),
+    ///The message was valid JSON but not a valid JSON-RPC request
+    InvalidRequest(serde_json::Error),
+    ///A control request referenced a state without a registered callback
+    UnknownState(Uuid),
+    ///A control request carried data that does not match the type of its value
+    InvalidControl(Uuid, ValidationError),
+    ///A fresh reading requested for a report state could not be reported
+    RefreshFailed(Uuid, String),
+    ///The message was neither a request nor a response
+    UnknownMessage(Value),
+    ///Wappsto answered a request with an error, and nobody was waiting for the answer. Errors in
+    ///response to reports are passed to their [`Ack`] instead.
+    Rejected(Option<String>, RpcError),
+    ///The connection to Wappsto was lost
+    Disconnected(io::Error),
+    ///An attempt to re-establish the connection failed. It will be retried.
+    ReconnectFailed(io::Error),
+    ///The network could not be saved to its store automatically. It will be saved again on the
+    ///next change.
+    SaveFailed(String),
+    ///The file backing the message queue could not be written. The messages are still queued in
+    ///memory.
+    QueueFailed(io::Error),
+}
+
+impl Display for CommunicationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Deserialize(e) => write!(f, "Deserialize error: {}", e),
+            Self::InvalidRequest(e) => write!(f, "Invalid request: {}", e),
+            Self::UnknownState(id) => write!(f, "Unknown state: {}", id),
+            Self::InvalidControl(id, e) => write!(f, "Invalid control of {}: {}", id, e),
+            Self::RefreshFailed(id, e) => write!(f, "Refresh of {} failed: {}", id, e),
+            Self::UnknownMessage(d) => write!(f, "Unknown message: {}", d),
+            Self::Rejected(Some(id), e) => write!(f, "Request {} rejected: {}", id, e),
+            Self::Rejected(None, e) => write!(f, "Request rejected: {}", e),
+            Self::Disconnected(e) => write!(f, "Disconnected: {}", e),
+            Self::ReconnectFailed(e) => write!(f, "Reconnect failed: {}", e),
+            Self::SaveFailed(e) => write!(f, "Saving the network failed: {}", e),
+            Self::QueueFailed(e) => write!(f, "Persisting the message queue failed: {}", e),
+        }
+    }
+}
+
+impl Error for CommunicationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Deserialize(e) | Self::InvalidRequest(e) => Some(e),
+            Self::Disconnected(e) | Self::ReconnectFailed(e) | Self::QueueFailed(e) => Some(e),
+            Self::Rejected(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+///Incremental decoder for the JSON-RPC byte stream. Bytes are accumulated across reads until at
+///least one complete JSON document is available, so messages may be split over several reads or
+///coalesced into one. Batches (top-level arrays) are split into their individual messages.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    frames: VecDeque<Value>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    ///Returns the next complete message, or `None` if more bytes are needed. A syntax error
+    ///discards everything buffered so far, as there is no reliable way to resynchronise inside
+    ///a malformed document.
+    pub fn next_frame(&mut self) -> Option<Result<Value, serde_json::Error>> {
+        if let Some(frame) = self.frames.pop_front() {
+            return Some(Ok(frame));
+        }
+
+        let mut stream = Deserializer::from_slice(&self.buffer).into_iter::<Value>();
+        let next = stream.next();
+        let consumed = stream.byte_offset();
+        match next {
+            None => {
+                self.buffer.clear();
+                None
+            }
+            Some(Err(e)) if e.is_eof() => None,
+            Some(Err(e)) => {
+                self.buffer.clear();
+                Some(Err(e))
+            }
+            Some(Ok(Value::Array(batch))) => {
+                self.buffer.drain(..consumed);
+                self.frames.extend(batch);
+                self.next_frame()
+            }
+            Some(Ok(frame)) => {
+                self.buffer.drain(..consumed);
+                Some(Ok(frame))
+            }
+        }
+    }
+}
+
+///Read whatever is available, returning `None` once the session has been closed
+fn read_all_from<T: Read>(
+    reader: &Mutex<T>,
+    buf: &mut [u8],
+    connected: &AtomicBool,
+) -> io::Result<Option<usize>> {
+    while connected.load(Ordering::SeqCst) {
+        let read = reader.lock().unwrap().read(buf);
         match read {
-            Ok(v) => break v,
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::yield_now(),
-            Err(_) => (),
+            Ok(v) => return Ok(Some(v)),
+            Err(ref e) if is_transient(e) => thread::yield_now(),
+            Err(e) => return Err(e),
         }
     }
+    Ok(None)
 }
 
-fn write_thread<T>(write: Arc<Mutex<T>>, receive: Receiver<String>)
+///Write messages until the stream fails, or until the session is stopped and every message
+///sent before that has been written.
+fn write_thread<T>(
+    write: &Mutex<T>,
+    first: Vec<String>,
+    receive: &Receiver<String>,
+    connected: &AtomicBool,
+    stopping: &AtomicBool,
+) -> Result<(), (io::Error, Vec<String>)>
 where
     T: Write + Send + 'static,
 {
-    loop {
-        let msg = receive.recv().unwrap();
-        write_all_to(&write, msg.as_bytes());
+    let mut first = first.into_iter();
+    while let Some(msg) = first.next() {
+        if let Err(e) = write_all_to(write, msg.as_bytes(), connected) {
+            return Err((e, iter::once(msg).chain(first).collect()));
+        }
+    }
+    while connected.load(Ordering::SeqCst) {
+        match receive.recv_timeout(POLL_INTERVAL) {
+            Ok(msg) => {
+                if let Err(e) = write_all_to(write, msg.as_bytes(), connected) {
+                    return Err((e, vec![msg]));
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if stopping.load(Ordering::SeqCst) => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
     }
+    Ok(())
 }
 
-fn write_all_to<T: Write>(writer: &Arc<Mutex<T>>, msg: &[u8]) {
-    loop {
-        let write = writer.lock().unwrap().write_all(msg);
+fn write_all_to<T: Write>(
+    writer: &Mutex<T>,
+    mut msg: &[u8],
+    connected: &AtomicBool,
+) -> io::Result<()> {
+    while !msg.is_empty() {
+        if !connected.load(Ordering::SeqCst) {
+            return Err(io::Error::from(ErrorKind::NotConnected));
+        }
+        let write = writer.lock().unwrap().write(msg);
         match write {
-            Ok(_) => break,
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::yield_now(),
-            Err(_) => break,
+            Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+            Ok(written) => msg = &msg[written..],
+            Err(ref e) if is_transient(e) => thread::yield_now(),
+            Err(e) => return Err(e),
         }
     }
+    Ok(())
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted)
+}
+
+///The latest report for each state. They are re-sent after a reconnect, after the network schema
+///as it is by then, so Wappsto reflects the state of the device even if messages were lost while
+///the connection was down.
+#[derive(Default)]
+pub struct Replay {
+    reports: HashMap<Uuid, String>,
+}
+
+impl Replay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Keep the reports in `msg`, whether it is a single report or a batch of them
+    pub fn record(&mut self, msg: &str) {
+        self.reports.extend(reports(msg));
+    }
+
+    ///The messages to send on a new connection: `schema`, then the `queued` messages in order,
+    ///then the latest report of every state that has no newer report among the queued ones.
+    ///Reports of states that are no longer `registered`, e.g. because they were deleted, are
+    ///forgotten.
+    pub fn resume(
+        &mut self,
+        schema: Option<String>,
+        queued: Vec<String>,
+        registered: &CallbackMap,
+    ) -> Vec<String> {
+        self.reports
+            .retain(|state, _| registered.contains_key(state));
+        let queued_states = queued
+            .iter()
+            .flat_map(|msg| reports(msg))
+            .map(|(state, _)| state)
+            .collect::<HashSet<Uuid>>();
+        let reports = self
+            .reports
+            .iter()
+            .filter(|(state, _)| !queued_states.contains(state))
+            .map(|(_, msg)| msg.clone())
+            .collect::<Vec<String>>();
+        schema.into_iter().chain(queued).chain(reports).collect()
+    }
 }
@@ -7,19 +7,21 @@ mod receiver {
     };
 
     use crate::{
-        communication::{self, CallbackMap},
-        rpc::{RpcData, RpcMethod, RpcRequest, RpcStateData},
-        schema::{Meta, MetaType},
+        communication::{
+            self, AckError, CallbackMap, CommunicationError, ErrorHandler, Pending, RequestHandler,
+        },
+        rpc::RpcErrorKind,
+        schema::ValidationError,
         stream_mock::StreamMock,
+        test_support::{control_state_rpc, ignore_errors},
     };
-    use chrono::Utc;
     use uuid::Uuid;
 
     pub const DEFAULT_ID: &str = "00000000-0000-0000-0000-000000000000";
 
     #[test]
     fn should_callback_on_control() {
-        let mut stream = StreamMock::new();
+        let stream = StreamMock::new();
         stream.receive(&control_state_rpc(
             "1",
             Uuid::parse_str(DEFAULT_ID).unwrap(),
@@ -28,27 +30,656 @@ mod receiver {
         let callback_arc = Arc::clone(&callback_was_called);
         let callback = move |_: String| {
             *callback_arc.lock().unwrap() = true;
+            Ok(())
         };
         let mut callbacks: CallbackMap = HashMap::new();
         callbacks.insert(
             Uuid::parse_str(DEFAULT_ID).unwrap(),
-            Arc::new(Mutex::new(Box::new(callback))),
+            RequestHandler::Control(Arc::new(Mutex::new(Arc::new(callback)))),
         );
 
-        communication::start(callbacks, stream);
+        communication::start(
+            Arc::new(Mutex::new(callbacks)),
+            Pending::default(),
+            ignore_errors(),
+            stream,
+        );
         sleep(Duration::from_millis(10));
         assert!(*callback_was_called.lock().unwrap())
     }
 
-    fn control_state_rpc(data: &str, id: Uuid) -> String {
+    #[test]
+    fn should_callback_on_each_coalesced_control() {
+        let stream = StreamMock::new();
+        let id = Uuid::parse_str(DEFAULT_ID).unwrap();
+        stream.receive(&(control_state_rpc("1", id) + &control_state_rpc("2", id)));
+        let received = Arc::new(Mutex::new(vec![]));
+        let received_arc = Arc::clone(&received);
+        let callback = move |data: String| {
+            received_arc.lock().unwrap().push(data);
+            Ok(())
+        };
+        let mut callbacks: CallbackMap = HashMap::new();
+        callbacks.insert(
+            id,
+            RequestHandler::Control(Arc::new(Mutex::new(Arc::new(callback)))),
+        );
+
+        communication::start(
+            Arc::new(Mutex::new(callbacks)),
+            Pending::default(),
+            ignore_errors(),
+            stream,
+        );
+        sleep(Duration::from_millis(10));
+        let received = received.lock().unwrap().clone();
+        assert_eq!(vec!["1", "2"], received)
+    }
+
+    #[test]
+    fn should_report_malformed_messages_and_keep_reading() {
+        let stream = StreamMock::new();
+        let id = Uuid::parse_str(DEFAULT_ID).unwrap();
+        stream.receive("{]");
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_arc = Arc::clone(&errors);
+        let on_error: ErrorHandler = Arc::new(Mutex::new(Box::new(move |e| {
+            errors_arc.lock().unwrap().push(e)
+        })));
+        let callback_was_called = Arc::new(Mutex::new(false));
+        let callback_arc = Arc::clone(&callback_was_called);
+        let mut callbacks: CallbackMap = HashMap::new();
+        callbacks.insert(
+            id,
+            RequestHandler::Control(Arc::new(Mutex::new(Arc::new(move |_| {
+                *callback_arc.lock().unwrap() = true;
+                Ok(())
+            })))),
+        );
+
+        communication::start(
+            Arc::new(Mutex::new(callbacks)),
+            Pending::default(),
+            on_error,
+            stream.clone(),
+        );
+        sleep(Duration::from_millis(10));
+        stream.receive(&control_state_rpc("1", id));
+        sleep(Duration::from_millis(10));
+
+        assert!(matches!(
+            errors.lock().unwrap()[0],
+            CommunicationError::Deserialize(_)
+        ));
+        assert!(stream.sent().contains("-32700"));
+        assert!(*callback_was_called.lock().unwrap())
+    }
+
+    #[test]
+    fn should_respond_with_error_on_unknown_state() {
+        let stream = StreamMock::new();
+        let id = Uuid::new_v4();
+        stream.receive(&control_state_rpc("1", id));
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_arc = Arc::clone(&errors);
+        let on_error: ErrorHandler = Arc::new(Mutex::new(Box::new(move |e| {
+            errors_arc.lock().unwrap().push(e)
+        })));
+
+        communication::start(Arc::default(), Pending::default(), on_error, stream.clone());
+        sleep(Duration::from_millis(10));
+
+        assert!(matches!(
+            errors.lock().unwrap()[0],
+            CommunicationError::UnknownState(unknown) if unknown == id
+        ));
+        assert!(stream.sent().contains("\"error\""));
+    }
+
+    #[test]
+    fn should_respond_with_error_on_rejected_control() {
+        let stream = StreamMock::new();
+        let id = Uuid::new_v4();
+        stream.receive(&control_state_rpc("11", id));
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_arc = Arc::clone(&errors);
+        let on_error: ErrorHandler = Arc::new(Mutex::new(Box::new(move |e| {
+            errors_arc.lock().unwrap().push(e)
+        })));
+        let mut callbacks: CallbackMap = HashMap::new();
+        callbacks.insert(
+            id,
+            RequestHandler::Control(Arc::new(Mutex::new(Arc::new(|_| {
+                Err(ValidationError::OutOfRange(11f64, 0f64, 10f64))
+            })))),
+        );
+
+        communication::start(
+            Arc::new(Mutex::new(callbacks)),
+            Pending::default(),
+            on_error,
+            stream.clone(),
+        );
+        sleep(Duration::from_millis(10));
+
+        assert!(matches!(
+            errors.lock().unwrap()[0],
+            CommunicationError::InvalidControl(rejected, _) if rejected == id
+        ));
+        assert!(stream.sent().contains("-32602"));
+        assert!(!stream.sent().contains("\"result\""));
+    }
+
+    #[test]
+    fn should_refresh_state_named_by_url_or_data() {
+        let stream = StreamMock::new();
+        let id = Uuid::new_v4();
+        stream.receive(&format!(
+            r#"{{"jsonrpc":"2.0","id":"1","method":"GET","params":{{"url":"/state/{}"}}}}"#,
+            id
+        ));
+        stream.receive(&format!(
+            r#"{{"jsonrpc":"2.0","id":"2","method":"GET","params":{{"url":"/state","data":{{"meta":{{"id":"{}"}},"status":"update"}}}}}}"#,
+            id
+        ));
+        let refreshes = Arc::new(Mutex::new(0));
+        let refreshes_arc = Arc::clone(&refreshes);
+        let mut callbacks: CallbackMap = HashMap::new();
+        callbacks.insert(
+            id,
+            RequestHandler::Refresh(Arc::new(Mutex::new(Arc::new(move || {
+                *refreshes_arc.lock().unwrap() += 1;
+                Ok(())
+            })))),
+        );
+
+        communication::start(
+            Arc::new(Mutex::new(callbacks)),
+            Pending::default(),
+            ignore_errors(),
+            stream.clone(),
+        );
+        sleep(Duration::from_millis(10));
+
+        assert_eq!(2, *refreshes.lock().unwrap());
+        assert_eq!(2, stream.sent().matches("\"result\"").count());
+    }
+
+    #[test]
+    fn should_respond_with_error_on_refresh_of_unknown_state() {
+        let stream = StreamMock::new();
+        let id = Uuid::new_v4();
+        stream.receive(&format!(
+            r#"{{"jsonrpc":"2.0","id":"1","method":"GET","params":{{"url":"/state/{}"}}}}"#,
+            id
+        ));
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_arc = Arc::clone(&errors);
+        let on_error: ErrorHandler = Arc::new(Mutex::new(Box::new(move |e| {
+            errors_arc.lock().unwrap().push(e)
+        })));
+
+        communication::start(Arc::default(), Pending::default(), on_error, stream.clone());
+        sleep(Duration::from_millis(10));
+
+        assert!(matches!(
+            errors.lock().unwrap()[0],
+            CommunicationError::UnknownState(unknown) if unknown == id
+        ));
+        assert!(stream.sent().contains("-32602"));
+    }
+
+    #[test]
+    fn should_acknowledge_and_handle_delete() {
+        let stream = StreamMock::new();
+        let id = Uuid::new_v4();
+        stream.receive(&format!(
+            r#"{{"jsonrpc":"2.0","id":"1","method":"DELETE","params":{{"url":"/network/{}/device/{}"}}}}"#,
+            Uuid::new_v4(),
+            id
+        ));
+        let deleted = Arc::new(Mutex::new(false));
+        let deleted_arc = Arc::clone(&deleted);
+        let mut callbacks: CallbackMap = HashMap::new();
+        callbacks.insert(
+            id,
+            RequestHandler::Delete(Arc::new(Mutex::new(Arc::new(move || {
+                *deleted_arc.lock().unwrap() = true
+            })))),
+        );
+
+        communication::start(
+            Arc::new(Mutex::new(callbacks)),
+            Pending::default(),
+            ignore_errors(),
+            stream.clone(),
+        );
+        sleep(Duration::from_millis(10));
+
+        assert!(*deleted.lock().unwrap());
+        assert!(stream.sent().contains("\"result\""));
+    }
+
+    #[test]
+    fn should_flush_and_close_stream_on_stop() {
+        let stream = StreamMock::new();
+        let session = communication::start(
+            Arc::default(),
+            Pending::default(),
+            ignore_errors(),
+            stream.clone(),
+        );
+        session.sender().send(String::from("last message")).unwrap();
+        session.stop().unwrap();
+        assert_eq!("last message", stream.sent());
+        assert!(stream.is_shut_down());
+    }
+
+    #[test]
+    fn should_pass_result_to_waiting_request() {
+        let stream = StreamMock::new();
+        stream.receive(r#"{"jsonrpc":"2.0","id":"report","result":{"value":true}}"#);
+        let pending = Pending::default();
+        let ack = pending.track("report");
+
+        communication::start(Arc::default(), pending, ignore_errors(), stream);
+
+        assert_eq!(serde_json::json!({"value": true}), ack.wait().unwrap());
+    }
+
+    #[test]
+    fn should_pass_error_to_waiting_request() {
+        let stream = StreamMock::new();
+        stream.receive(
+            r#"{"jsonrpc":"2.0","id":"report","error":{"code":-32602,"message":"Invalid data"}}"#,
+        );
+        let pending = Pending::default();
+        let ack = pending.track("report");
+
+        communication::start(Arc::default(), pending, ignore_errors(), stream);
+
+        assert!(matches!(
+            ack.wait(),
+            Err(AckError::Rejected(e)) if e.code == -32602 && e.message == "Invalid data"
+        ));
+    }
+
+    #[test]
+    fn should_time_out_unanswered_request() {
+        let pending = Pending::new(Duration::from_millis(10));
+        let ack = pending.track("report");
+
+        assert!(ack.poll().is_none());
+        assert!(matches!(ack.wait(), Err(AckError::TimedOut)));
+    }
+
+    #[test]
+    fn should_report_error_nobody_waits_for() {
+        let stream = StreamMock::new();
+        stream.receive(
+            r#"{"jsonrpc":"2.0","id":"unknown","error":{"code":-32001,"message":"Permission denied","data":{"url":"/network"}}}"#,
+        );
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_arc = Arc::clone(&errors);
+        let on_error: ErrorHandler = Arc::new(Mutex::new(Box::new(move |e| {
+            errors_arc.lock().unwrap().push(e)
+        })));
+
+        communication::start(Arc::default(), Pending::default(), on_error, stream);
+        sleep(Duration::from_millis(10));
+
+        let errors = errors.lock().unwrap();
+        match &errors[..] {
+            [CommunicationError::Rejected(Some(id), e)] => {
+                assert_eq!("unknown", id);
+                assert_eq!(RpcErrorKind::Server(-32001), e.kind());
+                assert_eq!("Permission denied", e.message);
+                assert_eq!(Some(serde_json::json!({"url": "/network"})), e.data);
+            }
+            errors => panic!("Unexpected errors: {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn should_ignore_result_nobody_waits_for() {
+        let stream = StreamMock::new();
+        stream.receive(r#"{"jsonrpc":"2.0","id":"unknown","result":{"value":true}}"#);
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_arc = Arc::clone(&errors);
+        let on_error: ErrorHandler = Arc::new(Mutex::new(Box::new(move |e| {
+            errors_arc.lock().unwrap().push(e)
+        })));
+
+        communication::start(Arc::default(), Pending::default(), on_error, stream);
+        sleep(Duration::from_millis(10));
+
+        assert!(errors.lock().unwrap().is_empty());
+    }
+}
+
+mod decoder {
+    use serde_json::json;
+
+    use crate::communication::FrameDecoder;
+
+    #[test]
+    fn should_wait_for_complete_message() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(br#"{"jsonrpc": "2.0", "#);
+        assert!(decoder.next_frame().is_none());
+        decoder.push(br#""id": "1"}"#);
+        assert_eq!(
+            json!({"jsonrpc": "2.0", "id": "1"}),
+            decoder.next_frame().unwrap().unwrap()
+        );
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn should_split_concatenated_messages() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(br#"{"id": "1"}{"id": "2"} {"id""#);
+        assert_eq!(json!({"id": "1"}), decoder.next_frame().unwrap().unwrap());
+        assert_eq!(json!({"id": "2"}), decoder.next_frame().unwrap().unwrap());
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn should_split_batches() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(br#"[{"id": "1"}, {"id": "2"}]"#);
+        assert_eq!(json!({"id": "1"}), decoder.next_frame().unwrap().unwrap());
+        assert_eq!(json!({"id": "2"}), decoder.next_frame().unwrap().unwrap());
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn should_decode_messages_larger_than_a_single_read() {
+        let mut decoder = FrameDecoder::new();
+        let message = serde_json::to_vec(&json!({ "data": "x".repeat(10_000) })).unwrap();
+        message.chunks(4096).for_each(|chunk| decoder.push(chunk));
+        assert_eq!(
+            10_000,
+            decoder.next_frame().unwrap().unwrap()["data"]
+                .as_str()
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn should_report_syntax_errors() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(b"{]");
+        assert!(decoder.next_frame().unwrap().is_err());
+        assert!(decoder.next_frame().is_none());
+    }
+}
+
+mod supervisor {
+    use std::{
+        io::{self, ErrorKind},
+        sync::{Arc, Mutex},
+        thread::sleep,
+        time::Duration,
+    };
+
+    use uuid::Uuid;
+
+    use crate::{
+        communication::{
+            self, CallbackMap, CommunicationError, ErrorHandler, Pending, RefreshHandler, Replay,
+            RequestHandler,
+        },
+        connection::Backoff,
+        queue::MessageQueue,
+        rpc::{RpcData, RpcMethod, RpcRequest, RpcType},
+        schema::Schema,
+        stream_mock::StreamMock,
+        test_support::{fast_backoff, report_rpc},
+    };
+
+    #[test]
+    fn should_reconnect_and_replay_schema_after_disconnect() {
+        let first = StreamMock::new();
+        let second = StreamMock::new();
+        let second_connect = second.clone();
+        let disconnected = Arc::new(Mutex::new(false));
+        let disconnected_arc = Arc::clone(&disconnected);
+        let on_error: ErrorHandler = Arc::new(Mutex::new(Box::new(move |e| {
+            if let CommunicationError::Disconnected(_) = e {
+                *disconnected_arc.lock().unwrap() = true
+            }
+        })));
+        let schema = publish_rpc();
+        let published = schema.clone();
+
+        communication::supervise(
+            Arc::default(),
+            Pending::default(),
+            on_error,
+            first.clone(),
+            move || Ok(second_connect.clone()),
+            fast_backoff(),
+            Box::new(move || Some(published.clone())),
+            Arc::new(Mutex::new(Replay::new())),
+            Arc::new(Mutex::new(MessageQueue::default())),
+        );
+        first.disconnect();
+        sleep(Duration::from_millis(100));
+
+        assert!(*disconnected.lock().unwrap());
+        assert_eq!(schema, second.sent());
+    }
+
+    #[test]
+    fn should_keep_sending_after_reconnect() {
+        let first = StreamMock::new();
+        let second = StreamMock::new();
+        let second_connect = second.clone();
+        let session = communication::supervise(
+            Arc::default(),
+            Pending::default(),
+            Arc::new(Mutex::new(Box::new(|_| {}))),
+            first.clone(),
+            move || Ok(second_connect.clone()),
+            fast_backoff(),
+            Box::new(|| None),
+            Arc::new(Mutex::new(Replay::new())),
+            Arc::new(Mutex::new(MessageQueue::default())),
+        );
+        first.disconnect();
+        sleep(Duration::from_millis(100));
+        session
+            .sender()
+            .send(String::from("after reconnect"))
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert!(second.sent().contains("after reconnect"));
+    }
+
+    #[test]
+    fn should_send_messages_queued_while_disconnected_after_schema() {
+        let first = StreamMock::new();
+        let second = StreamMock::new();
+        let second_connect = second.clone();
+        let schema = publish_rpc();
+        let published = schema.clone();
+        let session = communication::supervise(
+            Arc::default(),
+            Pending::default(),
+            Arc::new(Mutex::new(Box::new(|_| {}))),
+            first.clone(),
+            move || Ok(second_connect.clone()),
+            Backoff {
+                initial: Duration::from_millis(50),
+                jitter: 0f64,
+                ..Backoff::default()
+            },
+            Box::new(move || Some(published.clone())),
+            Arc::new(Mutex::new(Replay::new())),
+            Arc::new(Mutex::new(MessageQueue::default())),
+        );
+        first.disconnect();
+        sleep(Duration::from_millis(10));
+        session.sender().send(String::from("queued")).unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(schema + "queued", second.sent());
+    }
+
+    #[test]
+    fn should_stop_while_reconnecting() {
+        let stream = StreamMock::new();
+        let session = communication::supervise(
+            Arc::default(),
+            Pending::default(),
+            Arc::new(Mutex::new(Box::new(|_| {}))),
+            stream.clone(),
+            || Err(io::Error::from(ErrorKind::ConnectionRefused)),
+            fast_backoff(),
+            Box::new(|| None),
+            Arc::new(Mutex::new(Replay::new())),
+            Arc::new(Mutex::new(MessageQueue::default())),
+        );
+        stream.disconnect();
+        sleep(Duration::from_millis(20));
+        session.stop().unwrap();
+    }
+
+    #[test]
+    fn should_replay_only_latest_report_per_state() {
+        let id = Uuid::new_v4();
+        let mut replay = Replay::new();
+        replay.record(&report_rpc("1", id));
+        replay.record(&report_rpc("2", id));
+        let messages = replay.resume(None, vec![], &registered(&[id]));
+        assert_eq!(1, messages.len());
+        assert!(messages[0].contains("\"2\""));
+    }
+
+    #[test]
+    fn should_replay_latest_report_of_batch() {
+        let id = Uuid::new_v4();
+        let mut replay = Replay::new();
+        replay.record(&format!(
+            "[{},{}]",
+            report_rpc("1", id),
+            report_rpc("2", id)
+        ));
+        let messages = replay.resume(None, vec![], &registered(&[id]));
+        assert_eq!(1, messages.len());
+        assert!(messages[0].contains("\"2\""));
+    }
+
+    #[test]
+    fn should_send_batch_queued_while_disconnected_instead_of_older_report() {
+        let id = Uuid::new_v4();
+        let first = StreamMock::new();
+        let second = StreamMock::new();
+        let second_connect = second.clone();
+        let replay = Arc::new(Mutex::new(Replay::new()));
+        replay.lock().unwrap().record(&report_rpc("old", id));
+        let session = communication::supervise(
+            Arc::new(Mutex::new(registered(&[id]))),
+            Pending::default(),
+            Arc::new(Mutex::new(Box::new(|_| {}))),
+            first.clone(),
+            move || Ok(second_connect.clone()),
+            Backoff {
+                initial: Duration::from_millis(50),
+                jitter: 0f64,
+                ..Backoff::default()
+            },
+            Box::new(|| None),
+            replay,
+            Arc::new(Mutex::new(MessageQueue::default())),
+        );
+        first.disconnect();
+        sleep(Duration::from_millis(10));
+        let batch = format!("[{},{}]", report_rpc("1", id), report_rpc("2", id));
+        session.sender().send(batch.clone()).unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(batch, second.sent());
+    }
+
+    #[test]
+    fn should_forget_reports_of_unregistered_states() {
+        let kept = Uuid::new_v4();
+        let deleted = Uuid::new_v4();
+        let mut replay = Replay::new();
+        replay.record(&report_rpc("kept", kept));
+        replay.record(&report_rpc("deleted", deleted));
+
+        let messages = replay.resume(None, vec![], &registered(&[kept]));
+        assert_eq!(1, messages.len());
+        assert!(messages[0].contains("kept"));
+        assert!(replay
+            .resume(None, vec![], &registered(&[kept, deleted]))
+            .iter()
+            .all(|msg| !msg.contains("deleted")));
+    }
+
+    fn registered(states: &[Uuid]) -> CallbackMap {
+        states
+            .iter()
+            .map(|state| {
+                let refresh: RefreshHandler = Arc::new(Mutex::new(Arc::new(|| Ok(()))));
+                (*state, RequestHandler::Refresh(refresh))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_grow_backoff_exponentially_up_to_max() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            multiplier: 2f64,
+            jitter: 0f64,
+        };
+        assert_eq!(Duration::from_secs(1), backoff.delay(0));
+        assert_eq!(Duration::from_secs(4), backoff.delay(2));
+        assert_eq!(Duration::from_secs(10), backoff.delay(8));
+    }
+
+    #[test]
+    fn should_not_overflow_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::MAX,
+            multiplier: 1e300,
+            jitter: 0.5,
+        };
+        assert!(backoff.delay(0) <= Duration::from_millis(1500));
+        assert!(backoff.delay(10) >= Duration::MAX / 2);
+        assert!(backoff.delay(u32::MAX) >= Duration::MAX / 2);
+        let backoff = Backoff {
+            jitter: 0f64,
+            ..backoff
+        };
+        assert_eq!(Duration::MAX, backoff.delay(10));
+    }
+
+    #[test]
+    fn should_ignore_jitter_that_is_not_finite() {
+        for jitter in [f64::INFINITY, f64::NAN, f64::NEG_INFINITY] {
+            let backoff = Backoff {
+                jitter,
+                ..Backoff::default()
+            };
+            assert_eq!(Duration::from_secs(1), backoff.delay(0));
+        }
+    }
+
+    fn publish_rpc() -> String {
         serde_json::to_string(
             &RpcRequest::builder()
-                .method(RpcMethod::Put)
-                .data(RpcData::Data(RpcStateData::new(
-                    data,
-                    Utc::now(),
-                    Meta::new_with_uuid(id, MetaType::State),
-                )))
+                .method(RpcMethod::Post)
+                .on_type(RpcType::Network)
+                .data(RpcData::Schema(Schema::new("test", Uuid::new_v4())))
                 .create(),
         )
         .unwrap()
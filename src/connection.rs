@@ -1,10 +1,21 @@
-use openssl::ssl::{SslConnector, SslMethod};
-
-use std::{error::Error, net::TcpStream, sync::mpsc::Sender};
+use openssl::ssl::{ErrorCode, SslConnector, SslMethod, SslStream};
+use rand::Rng;
+
+use std::{
+    error::Error,
+    io,
+    net::{Shutdown, TcpStream},
+    sync::{mpsc::Sender, Arc, Mutex},
+    thread,
+    time::Duration,
+};
 
 use crate::{
     certs::Certs,
-    communication::{self, CallbackMap},
+    communication::{
+        self, CallbackRegistry, Close, ErrorHandler, Pending, Replay, SchemaSource, Session,
+    },
+    queue::MessageQueue,
 };
 
 const DEV: &[&str] = &["dev.", ":52005"];
@@ -15,76 +26,209 @@ const BASE_URL: &str = "wappsto.com";
 
 pub struct Connection {
     certs: Certs,
-    #[allow(dead_code)]
     url: &'static [&'static str],
+    backoff: Mutex<Backoff>,
+    session: Mutex<Option<Session>>,
 }
 
-pub trait Connect<Se>
+pub trait Connect<Se>: Send + Sync
 where
     Se: WrappedSend,
 {
     fn new(certs: Certs, server: WappstoServers) -> Self;
-    fn start(&self, callbacks: CallbackMap) -> Result<Se, Box<dyn Error>>;
+    ///Open the connection. Whenever it is opened again after being lost, the network is
+    ///published again as `schema` describes it by then.
+    fn start(
+        &self,
+        callbacks: CallbackRegistry,
+        pending: Pending,
+        on_error: ErrorHandler,
+        queue: Arc<Mutex<MessageQueue>>,
+        schema: SchemaSource,
+    ) -> Result<Se, Box<dyn Error>>;
+
+    ///Flush pending messages and close the connection. The async connection cannot block the
+    ///runtime, so it returns before this is done, and a connection started again waits for it.
+    fn stop(&self) -> Result<(), Box<dyn Error>>;
+
+    ///Configure how reconnection attempts are spaced out after the connection is lost
+    fn set_backoff(&self, _backoff: Backoff) {}
 }
 
 impl Connect<SendChannel> for Connection {
     fn new(certs: Certs, server: WappstoServers) -> Self {
-        let url = match server {
-            WappstoServers::DEV => DEV,
-            WappstoServers::QA => QA,
-            WappstoServers::STAGING => STAGING,
-            WappstoServers::PROD => PROD,
-        };
-        Self { certs, url }
+        Self {
+            certs,
+            url: server.url(),
+            backoff: Mutex::new(Backoff::default()),
+            session: Mutex::new(None),
+        }
     }
 
-    fn start(&self, callbacks: CallbackMap) -> Result<SendChannel, Box<dyn Error>> {
-        let mut ctx = SslConnector::builder(SslMethod::tls())?;
-        ctx.cert_store_mut().add_cert(self.certs.ca.clone())?;
-        ctx.set_certificate(&self.certs.certificate)?;
-        ctx.set_private_key(&self.certs.private_key)?;
+    fn start(
+        &self,
+        callbacks: CallbackRegistry,
+        pending: Pending,
+        on_error: ErrorHandler,
+        queue: Arc<Mutex<MessageQueue>>,
+        schema: SchemaSource,
+    ) -> Result<SendChannel, Box<dyn Error>> {
+        self.stop()?;
+        let connector = connector(&self.certs)?;
+        let url = self.url;
+
+        let stream = open(&connector, url)?;
+        let replay = Arc::new(Mutex::new(Replay::new()));
+        let session = communication::supervise(
+            callbacks,
+            pending,
+            on_error,
+            stream,
+            move || open(&connector, url),
+            *self.backoff.lock().unwrap(),
+            schema,
+            Arc::clone(&replay),
+            queue,
+        );
+        let send = session.sender();
+        self.session.lock().unwrap().replace(session);
+
+        Ok(SendChannel::new(send, replay))
+    }
 
-        let stream = TcpStream::connect(&(String::from(self.url[0]) + BASE_URL + self.url[1]))?;
-        let stream = ctx
-            .build()
-            .connect(&(String::from(self.url[0]) + BASE_URL), stream)?;
+    fn stop(&self) -> Result<(), Box<dyn Error>> {
+        let session = self.session.lock().unwrap().take();
+        match session {
+            Some(session) => session.stop(),
+            None => Ok(()),
+        }
+    }
 
-        stream.get_ref().set_nonblocking(true)?;
+    fn set_backoff(&self, backoff: Backoff) {
+        *self.backoff.lock().unwrap() = backoff
+    }
+}
+
+///A TLS connector that authenticates with the certificates of the network
+pub(crate) fn connector(certs: &Certs) -> Result<SslConnector, Box<dyn Error>> {
+    let mut ctx = SslConnector::builder(SslMethod::tls())?;
+    ctx.cert_store_mut().add_cert(certs.ca.clone())?;
+    ctx.set_certificate(&certs.certificate)?;
+    ctx.set_private_key(&certs.private_key)?;
+    Ok(ctx.build())
+}
 
-        Ok(SendChannel::new(communication::start(callbacks, stream)))
+///The host name and the address of a Wappsto server
+pub(crate) fn host(url: &[&str]) -> (String, String) {
+    let host = String::from(url[0]) + BASE_URL;
+    let address = host.clone() + url[1];
+    (host, address)
+}
+
+fn open(connector: &SslConnector, url: &[&str]) -> io::Result<SslStream<TcpStream>> {
+    let (host, address) = host(url);
+    let stream = TcpStream::connect(address)?;
+    let stream = connector
+        .connect(&host, stream)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    stream.get_ref().set_nonblocking(true)?;
+    Ok(stream)
+}
+
+impl Close for SslStream<TcpStream> {
+    fn close(&mut self) -> io::Result<()> {
+        loop {
+            match self.shutdown() {
+                Ok(_) => break,
+                Err(e) if e.code() == ErrorCode::WANT_WRITE => thread::yield_now(),
+                Err(e) => return Err(io::Error::other(e.to_string())),
+            }
+        }
+        self.get_ref().shutdown(Shutdown::Both)
+    }
+}
+
+///Exponential backoff between reconnection attempts. The n'th attempt waits
+///`initial * multiplier^n`, capped at `max`, and randomly adjusted by up to `jitter` (a fraction of
+///the delay) so that a fleet of devices does not reconnect in lockstep after an outage. Jitter
+///is limited to between 0 and 1, and is left out if it is not a finite number.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+}
+
+impl Backoff {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = i32::try_from(attempt).unwrap_or(i32::MAX);
+        let base = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        let base = base.min(self.max.as_secs_f64());
+        let jitter = match self.jitter {
+            jitter if jitter.is_finite() && jitter > 0f64 => {
+                let jitter = jitter.min(1f64);
+                rand::thread_rng().gen_range(-jitter..=jitter)
+            }
+            _ => 0f64,
+        };
+        Duration::try_from_secs_f64((base * (1f64 + jitter)).max(0f64)).unwrap_or(self.max)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            multiplier: 2f64,
+            jitter: 0.2,
+        }
     }
 }
 
 pub struct SendChannel {
     send: Sender<String>,
+    replay: Arc<Mutex<Replay>>,
 }
 
-pub trait WrappedSend {
+///The sending half of a connection. Values report through it from control and refresh handlers,
+///which run on the thread reading from the connection.
+pub trait WrappedSend: Send + 'static {
     fn send(&self, msg: String) -> Result<(), Box<dyn Error>>;
 }
 
 impl WrappedSend for SendChannel {
     fn send(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        self.replay.lock().unwrap().record(&msg);
         self.send.send(msg)?;
         Ok(())
     }
 }
 
 impl SendChannel {
-    pub fn new(send: Sender<String>) -> Self {
-        Self { send }
+    pub fn new(send: Sender<String>, replay: Arc<Mutex<Replay>>) -> Self {
+        Self { send, replay }
     }
 }
 
+#[derive(Default)]
 pub enum WappstoServers {
     DEV,
     QA,
     STAGING,
+    #[default]
     PROD,
 }
 
-impl Default for WappstoServers {
-    fn default() -> Self {
-        Self::PROD
+impl WappstoServers {
+    pub(crate) fn url(&self) -> &'static [&'static str] {
+        match self {
+            WappstoServers::DEV => DEV,
+            WappstoServers::QA => QA,
+            WappstoServers::STAGING => STAGING,
+            WappstoServers::PROD => PROD,
+        }
     }
 }
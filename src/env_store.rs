@@ -0,0 +1,79 @@
+use std::{env, error::Error};
+use uuid::Uuid;
+
+use crate::{
+    certs::Certs,
+    fs_store::{FsStore, Store},
+    schema::Schema,
+};
+
+pub const CA_VAR: &str = "WAPPSTO_CA";
+pub const CERTIFICATE_VAR: &str = "WAPPSTO_CERTIFICATE";
+pub const PRIVATE_KEY_VAR: &str = "WAPPSTO_PRIVATE_KEY";
+
+///Reads the certificates as PEM strings from environment variables, as injected by e.g. a
+///container orchestrator or secret manager. The network schema is still saved to a directory,
+///`network_instance/` by default.
+///# Example
+///```no_run
+/// # use wappsto_iot_rs::{connection::Connection, env_store::EnvStore, network::Network};
+/// # use std::error::Error;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+///     let store = EnvStore::default().schemas_in("/var/lib/wappsto/");
+///     let network: Network<Connection, EnvStore> = Network::with_store("my network", store)?;
+/// #   Ok(())
+/// # }
+///```
+pub struct EnvStore {
+    ca: String,
+    certificate: String,
+    private_key: String,
+    schemas: FsStore,
+}
+
+impl EnvStore {
+    ///Read the certificates from the given variables instead of `WAPPSTO_CA`,
+    ///`WAPPSTO_CERTIFICATE` and `WAPPSTO_PRIVATE_KEY`
+    pub fn vars(mut self, ca: &str, certificate: &str, private_key: &str) -> Self {
+        self.ca = String::from(ca);
+        self.certificate = String::from(certificate);
+        self.private_key = String::from(private_key);
+        self
+    }
+
+    ///Save the network schema in the given directory
+    pub fn schemas_in(mut self, dir: &str) -> Self {
+        self.schemas = FsStore::new("", dir);
+        self
+    }
+}
+
+impl Store for EnvStore {
+    fn load_certs(&self) -> Result<Certs, Box<dyn Error>> {
+        let read = |var: &str| env::var(var).map_err(|e| format!("{}: {}", var, e));
+        Certs::new(
+            &read(&self.ca)?,
+            &read(&self.certificate)?,
+            &read(&self.private_key)?,
+        )
+    }
+
+    fn save_schema(&self, schema: Schema) -> Result<(), Box<dyn Error>> {
+        self.schemas.save_schema(schema)
+    }
+
+    fn load_schema(&self, id: Uuid) -> Result<Option<Schema>, Box<dyn Error>> {
+        self.schemas.load_schema(id)
+    }
+}
+
+impl Default for EnvStore {
+    fn default() -> Self {
+        Self {
+            ca: String::from(CA_VAR),
+            certificate: String::from(CERTIFICATE_VAR),
+            private_key: String::from(PRIVATE_KEY_VAR),
+            schemas: FsStore::default(),
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use serde_json;
 use std::error::Error;
-use std::fs::{read_to_string, write, DirBuilder, File};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{copy, read_to_string, rename, write, DirBuilder, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::Path;
 use uuid::Uuid;
 
 use crate::certs::Certs;
@@ -9,15 +11,19 @@ use crate::schema::Schema;
 const CA_FILE: &str = "ca.crt";
 const CERT_FILE: &str = "client.crt";
 const KEY_FILE: &str = "client.key";
+const TEMP_EXTENSION: &str = ".tmp";
+const BACKUP_EXTENSION: &str = ".bak";
 
 pub struct FsStore {
     certificates: String,
     network_schema: String,
 }
-pub trait Store {
+pub trait Store: Send + Sync {
     fn load_certs(&self) -> Result<Certs, Box<dyn Error>>;
     fn save_schema(&self, schema: Schema) -> Result<(), Box<dyn Error>>;
-    fn load_schema(&self, id: Uuid) -> Option<Schema>;
+    ///Load the saved schema of the network with the given id, if there is one. A schema that
+    ///cannot be read is an error rather than `None`.
+    fn load_schema(&self, id: Uuid) -> Result<Option<Schema>, Box<dyn Error>>;
 }
 
 impl FsStore {
@@ -35,19 +41,23 @@ impl FsStore {
 
         write(
             self.certificates.clone() + CA_FILE,
-            &certs.ca.to_pem().unwrap(),
+            certs.ca.to_pem().unwrap(),
         )?;
         write(
             self.certificates.clone() + CERT_FILE,
-            &certs.certificate.to_pem().unwrap(),
+            certs.certificate.to_pem().unwrap(),
         )?;
         write(
             self.certificates.clone() + KEY_FILE,
-            &certs.private_key.private_key_to_pem_pkcs8().unwrap(),
+            certs.private_key.private_key_to_pem_pkcs8().unwrap(),
         )?;
 
         Ok(())
     }
+
+    fn schema_path(&self, id: Uuid) -> String {
+        self.network_schema.clone() + &id.to_string() + ".json"
+    }
 }
 
 impl Store for FsStore {
@@ -60,32 +70,91 @@ impl Store for FsStore {
         Certs::new(&ca, &certificate, &private_key)
     }
 
-    ///Save network schema to data store
+    ///Save network schema to data store. The schema is written to a temporary file that replaces
+    ///the saved one once it is complete, so an interrupted save leaves the previous schema intact.
+    ///The previous schema is kept as a backup as well, which is replaced the same way.
     fn save_schema(&self, schema: Schema) -> Result<(), Box<dyn Error>> {
         DirBuilder::new()
             .recursive(true)
-            .create(&self.network_schema)
-            .unwrap();
-
-        Ok(serde_json::to_writer(
-            &File::create(self.network_schema.clone() + &schema.meta.id.to_string() + ".json")
-                .unwrap(),
-            &schema,
-        )?)
+            .create(&self.network_schema)?;
+
+        let path = self.schema_path(schema.meta.id);
+        let temp = path.clone() + TEMP_EXTENSION;
+        let mut file = File::create(&temp)?;
+        serde_json::to_writer(&mut file, &schema)?;
+        file.sync_all()?;
+
+        if Path::new(&path).exists() {
+            back_up(&path)?;
+        }
+        rename(&temp, &path)?;
+        //Make the rename itself durable. Directories cannot be opened on every platform.
+        if let Ok(dir) = File::open(&self.network_schema) {
+            dir.sync_all().ok();
+        }
+        Ok(())
     }
-    ///Load network schema from data store
-    fn load_schema(&self, id: Uuid) -> Option<Schema> {
-        let contents =
-            match read_to_string(String::from(&self.network_schema) + &id.to_string() + ".json") {
-                Ok(s) => s,
-                Err(_) => return None,
-            };
-        match serde_json::from_str(&contents) {
-            Ok(s) => Some(s),
-            Err(_) => None,
+
+    ///Load network schema from data store. If the saved schema is corrupt, the backup of the
+    ///previous one is loaded instead; if that fails too, [`CorruptSchema`] is returned.
+    fn load_schema(&self, id: Uuid) -> Result<Option<Schema>, Box<dyn Error>> {
+        let path = self.schema_path(id);
+        match read_schema(&path, id) {
+            Err(e) if e.is::<CorruptSchema>() => {
+                match read_schema(&(path + BACKUP_EXTENSION), id) {
+                    Ok(Some(schema)) => Ok(Some(schema)),
+                    _ => Err(e),
+                }
+            }
+            loaded => loaded,
         }
     }
 }
+
+///Copy the file at `path` to its backup. The copy replaces the previous backup once it is complete.
+fn back_up(path: &str) -> io::Result<()> {
+    let backup = String::from(path) + BACKUP_EXTENSION;
+    let temp = backup.clone() + TEMP_EXTENSION;
+    copy(path, &temp)?;
+    OpenOptions::new().write(true).open(&temp)?.sync_all()?;
+    rename(&temp, &backup)
+}
+
+fn read_schema(path: &str, id: Uuid) -> Result<Option<Schema>, Box<dyn Error>> {
+    let contents = match read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|source| CorruptSchema { id, source }.into())
+}
+
+///A saved schema that could not be parsed, e.g. because it was damaged. Loading it fails rather
+///than starting over, as that would give the network's devices and values new ids.
+#[derive(Debug)]
+pub struct CorruptSchema {
+    pub id: Uuid,
+    pub source: serde_json::Error,
+}
+
+impl Display for CorruptSchema {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The saved schema of network {} is corrupt: {}",
+            self.id, self.source
+        )
+    }
+}
+
+impl Error for CorruptSchema {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
 impl Default for FsStore {
     fn default() -> Self {
         Self::new("certificates/", "network_instance/")
@@ -0,0 +1,313 @@
+mod fs_store {
+    use std::{
+        env,
+        fs::{read_dir, read_to_string, remove_dir_all, write},
+    };
+
+    use uuid::Uuid;
+
+    use crate::{
+        fs_store::{CorruptSchema, FsStore, Store},
+        schema::{
+            DeviceInfo, DeviceSchema, NumberSchema, Permission, Schema, ValueSchema, ValueType,
+        },
+    };
+
+    #[test]
+    fn should_persist_number_details() {
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        let dir = String::from(dir.to_str().unwrap()) + "/";
+        let store = FsStore::new(&dir, &dir);
+        let id = Uuid::new_v4();
+        let mut schema = Schema::new("test", id);
+        let mut device = DeviceSchema::new("device", Uuid::new_v4());
+        device.value.push(ValueSchema::new(
+            "temperature",
+            Permission::R,
+            ValueType::Number(
+                NumberSchema::new(-20f64, 50f64, 0.5, "°C").si_conversion("[K] = [°C] + 273.15"),
+            ),
+        ));
+        schema.device.push(device);
+
+        store.save_schema(schema).unwrap();
+        let loaded = store.load_schema(id).unwrap().unwrap();
+        remove_dir_all(&dir).unwrap();
+
+        match &loaded.device[0].value[0].value_type {
+            ValueType::Number(number) => {
+                assert_eq!(-20f64, number.min);
+                assert_eq!("°C", number.unit);
+                assert_eq!(Some("[K] = [°C] + 273.15"), number.si_conversion.as_deref());
+            }
+            _ => panic!("Expected a number"),
+        }
+    }
+
+    #[test]
+    fn should_persist_device_metadata() {
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        let dir = String::from(dir.to_str().unwrap()) + "/";
+        let store = FsStore::new(&dir, &dir);
+        let id = Uuid::new_v4();
+        let mut schema = Schema::new("test", id);
+        let mut device = DeviceSchema::new("device", Uuid::new_v4());
+        device.info = DeviceInfo {
+            manufacturer: Some(String::from("ACME")),
+            serial: Some(String::from("A-1")),
+            ..DeviceInfo::default()
+        };
+        let info = device.info.clone();
+        schema.device.push(device);
+
+        store.save_schema(schema).unwrap();
+        let loaded = store.load_schema(id).unwrap().unwrap();
+        remove_dir_all(&dir).unwrap();
+
+        assert_eq!(info, loaded.device[0].info);
+    }
+
+    #[test]
+    fn should_keep_previous_schema_as_backup() {
+        let (dir, store) = temp_store();
+        let id = Uuid::new_v4();
+
+        store.save_schema(Schema::new("old", id)).unwrap();
+        store.save_schema(Schema::new("new", id)).unwrap();
+        let loaded = store.load_schema(id).unwrap().unwrap();
+        let files = read_dir(&dir).unwrap().count();
+        let backup_contents = read_to_string(format!("{}{}.json.bak", dir, id)).unwrap();
+        remove_dir_all(&dir).unwrap();
+
+        assert_eq!("new", loaded.name);
+        assert!(backup_contents.contains("\"old\""));
+        assert_eq!(2, files);
+    }
+
+    #[test]
+    fn should_replace_unfinished_backup() {
+        let (dir, store) = temp_store();
+        let id = Uuid::new_v4();
+        store.save_schema(Schema::new("old", id)).unwrap();
+
+        write(format!("{}{}.json.bak.tmp", dir, id), "{\"name\": \"ol").unwrap();
+        store.save_schema(Schema::new("new", id)).unwrap();
+        let files = read_dir(&dir).unwrap().count();
+        let backup_contents = read_to_string(format!("{}{}.json.bak", dir, id)).unwrap();
+        remove_dir_all(&dir).unwrap();
+
+        assert!(backup_contents.contains("\"old\""));
+        assert_eq!(2, files);
+    }
+
+    #[test]
+    fn should_load_backup_if_schema_is_corrupt() {
+        let (dir, store) = temp_store();
+        let id = Uuid::new_v4();
+        store.save_schema(Schema::new("old", id)).unwrap();
+        store.save_schema(Schema::new("new", id)).unwrap();
+
+        write(format!("{}{}.json", dir, id), "{\"name\": \"ne").unwrap();
+        let loaded = store.load_schema(id);
+        remove_dir_all(&dir).unwrap();
+
+        assert_eq!("old", loaded.unwrap().unwrap().name);
+    }
+
+    #[test]
+    fn should_fail_to_load_corrupt_schema() {
+        let (dir, store) = temp_store();
+        let id = Uuid::new_v4();
+        store.save_schema(Schema::new("test", id)).unwrap();
+
+        write(format!("{}{}.json", dir, id), "").unwrap();
+        let loaded = store.load_schema(id);
+        remove_dir_all(&dir).unwrap();
+
+        let error = loaded.err().unwrap();
+        assert_eq!(id, error.downcast_ref::<CorruptSchema>().unwrap().id);
+    }
+
+    #[test]
+    fn should_load_nothing_if_never_saved() {
+        let (dir, store) = temp_store();
+
+        assert!(store.load_schema(Uuid::new_v4()).unwrap().is_none());
+        remove_dir_all(&dir).ok();
+    }
+
+    fn temp_store() -> (String, FsStore) {
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        let dir = String::from(dir.to_str().unwrap()) + "/";
+        let store = FsStore::new(&dir, &dir);
+        (dir, store)
+    }
+}
+
+mod memory_store {
+    use uuid::Uuid;
+
+    use crate::{
+        fs_store::Store,
+        fs_store_test::pem,
+        memory_store::InMemoryStore,
+        network::Network,
+        network_test::connection::{ConnectionMock, WrappedSendMock},
+        schema::Schema,
+    };
+
+    #[test]
+    fn should_load_certs_from_pem() {
+        let id = Uuid::new_v4();
+        let (ca, certificate, private_key) = pem(id);
+        let store = InMemoryStore::from_pem(&ca, &certificate, &private_key).unwrap();
+
+        assert_eq!(id, store.load_certs().unwrap().id);
+    }
+
+    #[test]
+    fn should_reject_invalid_pem() {
+        assert!(InMemoryStore::from_pem("ca", "certificate", "key").is_err());
+    }
+
+    #[test]
+    fn should_keep_schema() {
+        let id = Uuid::new_v4();
+        let (ca, certificate, private_key) = pem(id);
+        let store = InMemoryStore::from_pem(&ca, &certificate, &private_key).unwrap();
+
+        store.save_schema(Schema::new("test", id)).unwrap();
+
+        assert_eq!("test", store.load_schema(id).unwrap().unwrap().name);
+        assert!(store.load_schema(Uuid::new_v4()).unwrap().is_none());
+    }
+
+    #[test]
+    fn should_create_network_with_store() {
+        let id = Uuid::new_v4();
+        let (ca, certificate, private_key) = pem(id);
+        let store = InMemoryStore::from_pem(&ca, &certificate, &private_key).unwrap();
+
+        let network: Network<ConnectionMock, InMemoryStore, WrappedSendMock> =
+            Network::with_store("test", store).unwrap();
+
+        assert_eq!(id, network.id());
+    }
+}
+
+mod env_store {
+    use std::env;
+
+    use uuid::Uuid;
+
+    use crate::{env_store::EnvStore, fs_store::Store, fs_store_test::pem};
+
+    #[test]
+    fn should_load_certs_from_environment() {
+        let id = Uuid::new_v4();
+        let (ca, certificate, private_key) = pem(id);
+        env::set_var("ENV_STORE_TEST_CA", ca);
+        env::set_var("ENV_STORE_TEST_CERTIFICATE", certificate);
+        env::set_var("ENV_STORE_TEST_KEY", private_key);
+        let store = EnvStore::default().vars(
+            "ENV_STORE_TEST_CA",
+            "ENV_STORE_TEST_CERTIFICATE",
+            "ENV_STORE_TEST_KEY",
+        );
+
+        assert_eq!(id, store.load_certs().unwrap().id);
+    }
+
+    #[test]
+    fn should_name_missing_variable() {
+        let store = EnvStore::default().vars(
+            "ENV_STORE_MISSING_CA",
+            "ENV_STORE_MISSING_CERTIFICATE",
+            "ENV_STORE_MISSING_KEY",
+        );
+
+        let error = store.load_certs().err().unwrap();
+
+        assert!(error.to_string().contains("ENV_STORE_MISSING_CA"));
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite_store {
+    use std::{env, fs::remove_file};
+
+    use uuid::Uuid;
+
+    use crate::{
+        certs::Certs, fs_store::Store, fs_store_test::pem, schema::Schema,
+        sqlite_store::SqliteStore,
+    };
+
+    #[test]
+    fn should_persist_certs_and_schema() {
+        let id = Uuid::new_v4();
+        let (ca, certificate, private_key) = pem(id);
+        let path = env::temp_dir().join(format!("{}.db", Uuid::new_v4()));
+        let store = SqliteStore::open(&path).unwrap();
+        store
+            .save_certs(Certs::new(&ca, &certificate, &private_key).unwrap())
+            .unwrap();
+        store.save_schema(Schema::new("test", id)).unwrap();
+        drop(store);
+
+        let store = SqliteStore::open(&path).unwrap();
+        let certs = store.load_certs().unwrap();
+        let schema = store.load_schema(id).unwrap().unwrap();
+        remove_file(&path).unwrap();
+
+        assert_eq!(id, certs.id);
+        assert_eq!("test", schema.name);
+    }
+
+    #[test]
+    fn should_replace_saved_schema() {
+        let id = Uuid::new_v4();
+        let store = SqliteStore::open_in_memory().unwrap();
+
+        store.save_schema(Schema::new("old", id)).unwrap();
+        store.save_schema(Schema::new("new", id)).unwrap();
+
+        assert_eq!("new", store.load_schema(id).unwrap().unwrap().name);
+    }
+
+    #[test]
+    fn should_fail_without_certs() {
+        let store = SqliteStore::open_in_memory().unwrap();
+
+        assert!(store.load_certs().is_err());
+        assert!(store.load_schema(Uuid::new_v4()).unwrap().is_none());
+    }
+}
+
+///A self-signed certificate for the network with the given id, as PEM strings
+fn pem(id: uuid::Uuid) -> (String, String, String) {
+    use openssl::{
+        asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509::X509NameBuilder,
+        x509::X509,
+    };
+
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", &id.to_string()).unwrap();
+    let name = name.build();
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    let certificate = String::from_utf8(builder.build().to_pem().unwrap()).unwrap();
+    let private_key = String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    (certificate.clone(), certificate, private_key)
+}
@@ -7,6 +7,19 @@ pub mod create_network;
 ///Data store for network schematics
 pub mod fs_store;
 
+///Saving networks to their store as they change
+pub mod autosave;
+
+///Store that keeps everything in memory
+pub mod memory_store;
+
+///Store that reads certificates from environment variables
+pub mod env_store;
+
+///Store backed by a SQLite database
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
+
 ///SSL Certificates used with Wappsto
 pub mod certs;
 
@@ -18,8 +31,18 @@ pub mod network;
 
 pub mod communication;
 
-mod rpc;
-mod schema;
+///Connection to Wappsto for use from a tokio runtime
+#[cfg(feature = "async")]
+pub mod asynchronous;
+
+///Buffering of outgoing messages while there is no connection to Wappsto
+pub mod queue;
+
+///The network schema understood by Wappsto
+pub mod schema;
+
+///JSON-RPC messages exchanged with Wappsto, including the errors it responds with
+pub mod rpc;
 
 #[cfg(test)]
 mod network_test;
@@ -27,5 +50,23 @@ mod network_test;
 #[cfg(test)]
 mod communication_test;
 
+#[cfg(all(test, feature = "async"))]
+mod asynchronous_test;
+
+#[cfg(test)]
+mod fs_store_test;
+
+#[cfg(test)]
+mod autosave_test;
+
+#[cfg(test)]
+mod queue_test;
+
+#[cfg(test)]
+mod schema_test;
+
 #[cfg(test)]
 mod stream_mock;
+
+#[cfg(test)]
+mod test_support;
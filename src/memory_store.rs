@@ -0,0 +1,44 @@
+use std::{collections::HashMap, error::Error, sync::Mutex};
+use uuid::Uuid;
+
+use crate::{certs::Certs, fs_store::Store, schema::Schema};
+
+///Keeps the certificates and network schema in memory, e.g. for tests or containers that are
+///set up from scratch on every start. Nothing is written to disk.
+pub struct InMemoryStore {
+    certs: Certs,
+    schemas: Mutex<HashMap<Uuid, Schema>>,
+}
+
+impl InMemoryStore {
+    pub fn new(certs: Certs) -> Self {
+        Self {
+            certs,
+            schemas: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///Use certificates given as PEM strings, e.g. fetched from a secret manager
+    pub fn from_pem(
+        ca: &str,
+        certificate: &str,
+        private_key: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::new(Certs::new(ca, certificate, private_key)?))
+    }
+}
+
+impl Store for InMemoryStore {
+    fn load_certs(&self) -> Result<Certs, Box<dyn Error>> {
+        Ok(self.certs.clone())
+    }
+
+    fn save_schema(&self, schema: Schema) -> Result<(), Box<dyn Error>> {
+        self.schemas.lock().unwrap().insert(schema.meta.id, schema);
+        Ok(())
+    }
+
+    fn load_schema(&self, id: Uuid) -> Result<Option<Schema>, Box<dyn Error>> {
+        Ok(self.schemas.lock().unwrap().get(&id).cloned())
+    }
+}
@@ -1,299 +1,788 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
 use std::{
-    cell::{Ref, RefCell},
     collections::HashMap,
     error::Error,
+    iter,
+    marker::PhantomData,
     ops::Deref,
-    rc::Rc,
-    sync::{Arc, Mutex, MutexGuard},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, Weak,
+    },
+    time::Duration,
 };
 use uuid::Uuid;
 
+#[cfg(feature = "async")]
+use crate::asynchronous::AsyncAck;
+#[cfg(feature = "async")]
+use std::future::Future;
+
 use crate::{
+    autosave::{Autosave, Changes},
     certs::Certs,
-    communication::CallbackMap,
-    connection::{Connect, Connection, SendChannel, WappstoServers, WrappedSend},
+    communication::{
+        Ack, CallbackMap, CallbackRegistry, CommunicationError, ControlHandler, ErrorHandler,
+        Pending, RefreshHandler, RequestHandler, SchemaSource,
+    },
+    connection::{Backoff, Connect, Connection, SendChannel, WappstoServers, WrappedSend},
     fs_store::{FsStore, Store},
+    queue::MessageQueue,
     rpc::{RpcData, RpcMethod, RpcRequest, RpcStateData, RpcType},
     schema::{
-        DeviceSchema, Meta, MetaType, NumberSchema, Permission, Schema, State, StateType,
-        ValueSchema,
+        DeviceInfo, DeviceSchema, FromData, IntoData, Meta, MetaType, Permission, Schema, State,
+        StateType, ValidationError, ValueSchema, ValueType,
     },
 };
 
+///A handle to a network. Networks, devices and values can be shared between threads, and
+///clones of a handle refer to the same network.
 pub struct Network<C = Connection, St = FsStore, Se = SendChannel>
 where
     C: Connect<Se>,
-    St: Store + Default,
+    St: Store,
     Se: WrappedSend,
 {
-    pub inner: Rc<RefCell<InnerNetwork<C, St, Se>>>,
+    pub inner: Arc<RwLock<InnerNetwork<C, St, Se>>>,
 }
 
-impl<C, St, Se> Network<C, St, Se>
+impl<C, St, Se> Clone for Network<C, St, Se>
 where
     C: Connect<Se>,
-    St: Store + Default,
+    St: Store,
     Se: WrappedSend,
 {
-    pub fn new(name: &str) -> Result<Self, Box<dyn Error>> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<C, St, Se> Network<C, St, Se>
+where
+    C: Connect<Se> + 'static,
+    St: Store + 'static,
+    Se: WrappedSend,
+{
+    pub fn new(name: &str) -> Result<Self, Box<dyn Error>>
+    where
+        St: Default,
+    {
         Self::new_at(WappstoServers::default(), name)
     }
 
-    pub fn new_at(server: WappstoServers, name: &str) -> Result<Self, Box<dyn Error>> {
-        let inner = InnerNetwork::new_at(server, name)?;
+    pub fn new_at(server: WappstoServers, name: &str) -> Result<Self, Box<dyn Error>>
+    where
+        St: Default,
+    {
+        Self::with_store_at(server, name, St::default())
+    }
+
+    ///Create a network whose certificates and schema are kept in the given store, e.g. an
+    ///[`InMemoryStore`](crate::memory_store::InMemoryStore) or an
+    ///[`EnvStore`](crate::env_store::EnvStore).
+    pub fn with_store(name: &str, store: St) -> Result<Self, Box<dyn Error>> {
+        Self::with_store_at(WappstoServers::default(), name, store)
+    }
+
+    pub fn with_store_at(
+        server: WappstoServers,
+        name: &str,
+        store: St,
+    ) -> Result<Self, Box<dyn Error>> {
+        let inner = InnerNetwork::with_store_at(server, name, store)?;
         Ok(Self {
-            inner: Rc::new(RefCell::new(inner)),
+            inner: Arc::new(RwLock::new(inner)),
         })
     }
 
+    ///Create a device without any metadata. Use [`device_builder`](Self::device_builder) to
+    ///describe it further.
     pub fn create_device(&self, name: &str) -> Device<Se> {
-        self.inner.borrow_mut().create_device(name)
+        self.device_builder(name).create()
     }
 
+    pub fn device_builder(&self, name: &str) -> DeviceBuilder<'_, C, St, Se> {
+        DeviceBuilder::new(self, name)
+    }
+
+    ///Connect to Wappsto and publish the network. The network is not locked while connecting, so
+    ///handlers of a previous connection may use it while that connection is closed.
     pub fn start(&self) -> Result<(), Box<dyn Error>> {
-        self.inner.borrow_mut().start()
+        let network = Arc::downgrade(&self.inner);
+        let schema: SchemaSource = Box::new(move || {
+            let network = network.upgrade()?;
+            let publication = network.read().unwrap().publication().ok();
+            publication
+        });
+        let connect = self.inner.write().unwrap().prepare_start(schema)?;
+        let send = connect()?;
+        self.inner.write().unwrap().started(send)
     }
 
+    ///Delete a device and all of its values, both locally and in Wappsto
+    pub fn delete_device(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.inner.write().unwrap().delete_device(name)
+    }
+
+    ///Close the connection after sending everything reported so far, and save the schema. The
+    ///network is not locked while the connection is closed, so handlers still running may use it.
     pub fn stop(&self) -> Result<(), Box<dyn Error>> {
-        self.inner.borrow_mut().stop()
+        let connection = self.inner.read().unwrap().disconnect();
+        connection.stop()?;
+        self.inner.write().unwrap().stopped()
     }
 
-    #[cfg(test)]
-    pub fn new_with_store(name: &str, store: St) -> Self {
-        Self {
-            inner: Rc::new(RefCell::new(InnerNetwork::new_with_store(name, store))),
-        }
+    ///Replace the queue that holds messages sent while there is no connection to Wappsto. By
+    ///default up to 1000 messages are kept in memory, dropping the oldest.
+    pub fn set_message_queue(&self, queue: MessageQueue) {
+        self.inner.read().unwrap().set_message_queue(queue)
+    }
+
+    ///Configure how reconnection attempts are spaced out if the connection to Wappsto is lost
+    pub fn set_backoff(&self, backoff: Backoff) {
+        self.inner.read().unwrap().connection.set_backoff(backoff)
+    }
+
+    ///How long to wait for Wappsto to acknowledge a report before giving up on it. Defaults to
+    ///30 seconds.
+    pub fn set_request_timeout(&self, timeout: Duration) {
+        self.inner.read().unwrap().pending.set_timeout(timeout)
+    }
+
+    ///Configure when devices and values created or changed are saved to the store, or turn
+    ///automatic saving off again with `None`. Off by default, in which case the network is only
+    ///saved when stopped or when something is deleted. Saving failures are passed to the error
+    ///handler.
+    pub fn set_autosave(&self, autosave: Option<Autosave>) {
+        let changes = self.inner.read().unwrap().changes.clone();
+        let autosave = match autosave {
+            Some(autosave) => autosave,
+            None => return changes.stop(),
+        };
+        let network = Arc::downgrade(&self.inner);
+        changes.autosave(autosave, move || match Weak::upgrade(&network) {
+            Some(network) => {
+                let mut network = network.write().unwrap();
+                network.prune();
+                if let Err(e) = network.save() {
+                    network.error_handler.lock().unwrap()(CommunicationError::SaveFailed(
+                        e.to_string(),
+                    ))
+                }
+                true
+            }
+            None => false,
+        });
+    }
+
+    ///Register a handler for errors in the communication with Wappsto, such as malformed
+    ///messages or controls for unknown states. Defaults to printing the error to stderr.
+    pub fn on_error(&self, handler: Box<dyn Fn(CommunicationError) + Send + Sync>) {
+        self.inner.read().unwrap().on_error(handler)
+    }
+
+    ///Register a handler for devices and values deleted in Wappsto. The handler is called as soon
+    ///as the deletion arrives; the device or value is removed from the network, and from the
+    ///store, the next time the network is used.
+    pub fn on_delete(&self, handler: Box<dyn Fn(Deleted) + Send + Sync>) {
+        *self.inner.read().unwrap().delete_handler.lock().unwrap() = handler
     }
 
     #[cfg(test)]
-    pub fn connection(&self) -> Rc<C> {
-        self.inner.borrow().connection()
+    pub fn connection(&self) -> Arc<C> {
+        self.inner.read().unwrap().connection()
     }
 
     #[cfg(test)]
-    pub fn store(&self) -> Rc<St> {
-        self.inner.borrow().store()
+    pub fn store(&self) -> Arc<St> {
+        self.inner.read().unwrap().store()
     }
 
     #[cfg(test)]
     pub fn device_named(&self, name: &str) -> Option<Device<Se>> {
-        self.inner.borrow().devices.get(name).cloned()
+        self.inner.read().unwrap().devices.get(name).cloned()
     }
 
     #[cfg(test)]
     pub fn devices_is_empty(&self) -> bool {
-        self.inner.borrow().devices.is_empty()
+        self.inner.read().unwrap().devices.is_empty()
     }
 
     #[cfg(test)]
     pub fn id(&self) -> Uuid {
-        self.inner.borrow().id.clone()
+        self.inner.read().unwrap().id
     }
 }
 
 pub struct InnerNetwork<C = Connection, St = FsStore, Se = SendChannel>
 where
     C: Connect<Se>,
-    St: Store + Default,
+    St: Store,
     Se: WrappedSend,
 {
     pub name: String,
     pub id: Uuid,
-    connection: Rc<C>,
-    store: Rc<St>,
+    connection: Arc<C>,
+    store: Arc<St>,
     devices: HashMap<String, Device<Se>>,
     pub send: Arc<Mutex<Option<Se>>>,
+    queue: Arc<Mutex<MessageQueue>>,
+    error_handler: ErrorHandler,
+    delete_handler: DeleteHook,
+    callbacks: CallbackRegistry,
+    pending: Pending,
+    changes: Changes,
 }
 
 impl<C, St, Se> InnerNetwork<C, St, Se>
 where
     C: Connect<Se>,
-    St: Store + Default,
+    St: Store,
     Se: WrappedSend,
 {
-    pub fn new(name: &str) -> Result<Self, Box<dyn Error>> {
+    pub fn new(name: &str) -> Result<Self, Box<dyn Error>>
+    where
+        St: Default,
+    {
         Self::new_at(WappstoServers::default(), name)
     }
 
-    pub fn new_at(server: WappstoServers, name: &str) -> Result<Self, Box<dyn Error>> {
-        let store = Rc::new(St::default());
+    pub fn new_at(server: WappstoServers, name: &str) -> Result<Self, Box<dyn Error>>
+    where
+        St: Default,
+    {
+        Self::with_store_at(server, name, St::default())
+    }
+
+    pub fn with_store_at(
+        server: WappstoServers,
+        name: &str,
+        store: St,
+    ) -> Result<Self, Box<dyn Error>> {
+        let store = Arc::new(store);
         let certs = store.load_certs()?;
-        let devices = Self::parse_schema(&store, &certs);
-        Ok(Self {
+        let devices = Self::parse_schema(&store, &certs)?;
+        let network = Self {
             name: String::from(name),
             id: certs.id,
-            connection: Rc::new(C::new(certs, server)),
+            connection: Arc::new(C::new(certs, server)),
             store,
             devices,
             send: Arc::new(Mutex::new(None)),
-        })
+            queue: Arc::new(Mutex::new(MessageQueue::default())),
+            error_handler: default_error_handler(),
+            delete_handler: default_delete_hook(),
+            callbacks: Arc::default(),
+            pending: Pending::default(),
+            changes: Changes::default(),
+        };
+        network.adopt_devices();
+        Ok(network)
     }
 
-    pub fn create_device(&mut self, name: &str) -> Device<Se> {
-        let device = self
+    ///Create a device, or update the metadata of an existing device of the same name
+    pub fn create_device(&mut self, name: &str, info: DeviceInfo) -> Device<Se> {
+        self.prune();
+        let old = self
             .devices
-            .entry(String::from(name))
-            .and_modify(|d| d.inner.borrow_mut().send = Arc::clone(&self.send))
-            .or_insert_with(|| {
-                Device::new(InnerDevice::new(
-                    name,
-                    Uuid::new_v4(),
-                    Arc::clone(&self.send),
-                ))
-            });
-        Device::clone(device)
+            .get(name)
+            .map(|d| DeviceSchema::from(d.inner.read().unwrap()));
+        let device = self.devices.entry(String::from(name)).or_insert_with(|| {
+            Device::new(InnerDevice::new(
+                name,
+                Uuid::new_v4(),
+                Arc::clone(&self.send),
+                Arc::clone(&self.queue),
+                Arc::clone(&self.callbacks),
+            ))
+        });
+        let device = Device::clone(device);
+        self.adopt(&mut device.inner.write().unwrap());
+        device.inner.write().unwrap().info = info;
+        device
+            .inner
+            .read()
+            .unwrap()
+            .register(&mut self.callbacks.lock().unwrap());
+        self.publish_device(old, DeviceSchema::from(device.inner.read().unwrap()));
+        self.changes.notify();
+        device
     }
 
-    pub fn start(&mut self) -> Result<(), Box<dyn Error>> {
-        self.send
-            .lock()
-            .unwrap()
-            .replace(self.connection.start(self.callbacks())?);
-        self.publish()?;
-        Ok(())
+    ///Let a device send through the network, and register its handlers with the network
+    fn adopt(&self, device: &mut InnerDevice<Se>) {
+        device.send = Arc::clone(&self.send);
+        device.queue = Arc::clone(&self.queue);
+        device.callbacks = Arc::clone(&self.callbacks);
+        device.delete_hook = Arc::clone(&self.delete_handler);
+        device.pending = self.pending.clone();
+        device.changes = self.changes.clone();
     }
 
-    pub fn stop(&mut self) -> Result<(), Box<dyn Error>> {
-        let schema: Schema = self.into();
-        self.store.save_schema(schema)?;
+    fn adopt_devices(&self) {
+        self.devices
+            .values()
+            .for_each(|d| self.adopt(&mut d.inner.write().unwrap()));
+    }
+
+    ///Let Wappsto know about a device created or changed after the network was started. New
+    ///devices are posted on their own; for existing ones, only the fields that changed are sent.
+    fn publish_device(&self, old: Option<DeviceSchema>, new: DeviceSchema) {
+        let request = match old {
+            None => RpcRequest::builder()
+                .method(RpcMethod::Post)
+                .on_type(RpcType::NetworkDevices(self.id))
+                .data(RpcData::Device(new)),
+            Some(old) => match changed_fields(&old, &new, "value") {
+                Some(changed) => RpcRequest::builder()
+                    .method(RpcMethod::Put)
+                    .on_type(RpcType::Device(new.meta.id))
+                    .data(RpcData::Partial(changed)),
+                None => return,
+            },
+        };
+        send_if_started(&self.send, &self.queue, request.create())
+    }
+
+    ///Save and register everything there is to publish. Returns the function that connects,
+    ///which needs nothing from the network. `schema` is published after every reconnect.
+    #[allow(clippy::type_complexity)]
+    fn prepare_start(
+        &mut self,
+        schema: SchemaSource,
+    ) -> Result<impl FnOnce() -> Result<Se, Box<dyn Error>>, Box<dyn Error>> {
+        if self.prune() {
+            self.save()?;
+        }
+        *self.callbacks.lock().unwrap() = self.collect_callbacks();
+        let connection = Arc::clone(&self.connection);
+        let callbacks = Arc::clone(&self.callbacks);
+        let pending = self.pending.clone();
+        let on_error = Arc::clone(&self.error_handler);
+        let queue = Arc::clone(&self.queue);
+        Ok(move || connection.start(callbacks, pending, on_error, queue, schema))
+    }
+
+    ///Publish the network and send what was queued through `send`, before anything else can
+    ///send through it. The queue stays locked meanwhile, so messages sent concurrently are queued
+    ///behind the flushed ones and sent in order.
+    fn started(&mut self, send: Se) -> Result<(), Box<dyn Error>> {
+        let mut queue = self.queue.lock().unwrap();
+        send.send(self.publication()?)?;
+        for msg in queue.drain() {
+            send.send(msg)?;
+        }
+        self.send.lock().unwrap().replace(send);
         Ok(())
     }
 
-    fn publish(&mut self) -> Result<(), Box<dyn Error>> {
-        let schema: Schema = self.into();
-        self.send
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .send(serde_json::to_string(
+    ///Replace the queue that holds messages sent while there is no connection. Messages already
+    ///queued are carried over, subject to the limits of the new queue.
+    pub fn set_message_queue(&self, mut queue: MessageQueue) {
+        queue.set_error_handler(Arc::clone(&self.error_handler));
+        let mut current = self.queue.lock().unwrap();
+        current.drain().into_iter().for_each(|msg| queue.push(msg));
+        *current = queue;
+    }
+
+    pub fn on_error(&self, handler: Box<dyn Fn(CommunicationError) + Send + Sync>) {
+        *self.error_handler.lock().unwrap() = handler
+    }
+
+    ///Stop sending through the connection. Returns the connection, to be stopped.
+    fn disconnect(&self) -> Arc<C> {
+        self.send.lock().unwrap().take();
+        Arc::clone(&self.connection)
+    }
+
+    fn stopped(&mut self) -> Result<(), Box<dyn Error>> {
+        self.prune();
+        self.save()
+    }
+
+    pub fn delete_device(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.prune();
+        let device = self
+            .devices
+            .remove(name)
+            .ok_or_else(|| format!("No device named {}", name))?;
+        let device = device.inner.read().unwrap();
+        unregister(&self.callbacks, device.registered_ids());
+        send_or_queue(
+            &self.send,
+            &self.queue,
+            serde_json::to_string(
                 &RpcRequest::builder()
-                    .method(RpcMethod::Post)
-                    .on_type(RpcType::Network)
-                    .data(RpcData::Schema(schema))
+                    .method(RpcMethod::Delete)
+                    .on_type(RpcType::Device(device.id))
                     .create(),
-            )?)?;
-        Ok(())
+            )?,
+        );
+        self.save()
+    }
+
+    fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        let schema: Schema = (&*self).into();
+        self.store.save_schema(schema)
     }
 
-    fn parse_schema(store: &St, certs: &Certs) -> HashMap<String, Device<Se>> {
-        if let Some(schema) = store.load_schema(certs.id) {
-            schema
+    ///The message that publishes the network as it is now
+    fn publication(&self) -> Result<String, serde_json::Error> {
+        let schema: Schema = self.into();
+        serde_json::to_string(
+            &RpcRequest::builder()
+                .method(RpcMethod::Post)
+                .on_type(RpcType::Network)
+                .data(RpcData::Schema(schema))
+                .create(),
+        )
+    }
+
+    fn parse_schema(
+        store: &St,
+        certs: &Certs,
+    ) -> Result<HashMap<String, Device<Se>>, Box<dyn Error>> {
+        Ok(match store.load_schema(certs.id)? {
+            Some(schema) => schema
                 .device
                 .into_iter()
                 .map(|d| (d.name.clone(), Device::from(d)))
-                .collect::<HashMap<String, Device<Se>>>()
-        } else {
-            HashMap::new()
-        }
+                .collect::<HashMap<String, Device<Se>>>(),
+            None => HashMap::new(),
+        })
     }
 
-    fn callbacks(&self) -> CallbackMap {
+    ///Remove the devices and values deleted in Wappsto. Returns whether anything was removed.
+    fn prune(&mut self) -> bool {
+        let devices = self.devices.len();
+        let callbacks = &self.callbacks;
+        self.devices.retain(|_, d| {
+            let device = d.inner.read().unwrap();
+            let deleted = device.deleted.load(Ordering::SeqCst);
+            if deleted {
+                unregister(callbacks, device.registered_ids());
+            }
+            !deleted
+        });
         self.devices
-            .iter()
-            .fold(HashMap::new(), |mut all_callbacks, (_, device)| {
-                device.inner.borrow().values.iter().for_each(|(_, value)| {
-                    value
-                        .inner
-                        .lock()
-                        .unwrap()
-                        .control
-                        .as_ref()
-                        .and_then(|c| all_callbacks.insert(c.inner.id, c.inner.callback.clone()));
-                });
+            .values()
+            .fold(devices != self.devices.len(), |pruned, d| {
+                d.inner.write().unwrap().prune() || pruned
+            })
+    }
+
+    fn collect_callbacks(&self) -> CallbackMap {
+        self.devices
+            .values()
+            .fold(HashMap::new(), |mut all_callbacks, device| {
+                device.inner.read().unwrap().register(&mut all_callbacks);
                 all_callbacks
             })
     }
 
     #[cfg(test)]
-    pub fn connection(&self) -> Rc<C> {
-        Rc::clone(&self.connection)
+    pub fn connection(&self) -> Arc<C> {
+        Arc::clone(&self.connection)
     }
 
     #[cfg(test)]
-    pub fn store(&self) -> Rc<St> {
-        Rc::clone(&self.store)
+    pub fn store(&self) -> Arc<St> {
+        Arc::clone(&self.store)
     }
+}
 
-    #[cfg(test)]
-    pub fn new_with_store(name: &str, store: St) -> Self {
-        let certs = store.load_certs().unwrap();
-        let id = certs.id;
-        let devices = Self::parse_schema(&store, &certs);
-        Self {
-            name: String::from(name),
-            id,
-            store: Rc::new(store),
-            devices,
-            connection: Rc::new(C::new(certs, WappstoServers::default())),
-            send: Arc::new(Mutex::new(None)),
-        }
+fn default_error_handler() -> ErrorHandler {
+    Arc::new(Mutex::new(Box::new(|e| eprintln!("{}", e))))
+}
+
+type DeleteHook = Arc<Mutex<Box<dyn Fn(Deleted) + Send + Sync>>>;
+
+fn default_delete_hook() -> DeleteHook {
+    Arc::new(Mutex::new(Box::new(|_| {})))
+}
+
+///A device or value deleted in Wappsto
+#[derive(Clone, Debug, PartialEq)]
+pub enum Deleted {
+    Device {
+        id: Uuid,
+        name: String,
+    },
+    Value {
+        id: Uuid,
+        name: String,
+        device: String,
+    },
+}
+
+fn unregister(callbacks: &CallbackRegistry, ids: Vec<Uuid>) {
+    let mut callbacks = callbacks.lock().unwrap();
+    ids.iter().for_each(|id| {
+        callbacks.remove(id);
+    });
+}
+
+///Send `msg` if the network is connected, or else queue it
+fn send_or_queue<Se: WrappedSend>(
+    send: &Mutex<Option<Se>>,
+    queue: &Mutex<MessageQueue>,
+    msg: String,
+) {
+    let mut queue = queue.lock().unwrap();
+    let sent = match send.lock().unwrap().as_ref() {
+        Some(send) => send.send(msg.clone()).is_ok(),
+        None => false,
+    };
+    if !sent {
+        queue.push(msg)
+    }
+}
+
+///Send `request` if the network has been started. Before that, everything is published along
+///with the network.
+fn send_if_started<Se: WrappedSend>(
+    send: &Mutex<Option<Se>>,
+    queue: &Mutex<MessageQueue>,
+    request: RpcRequest,
+) {
+    if send.lock().unwrap().is_none() {
+        return;
+    }
+    if let Ok(msg) = serde_json::to_string(&request) {
+        send_or_queue(send, queue, msg)
     }
 }
 
+///The top-level fields of `new` that differ from `old`, other than `children`, along with the
+///meta of `new`. Fields that were removed are null. None if nothing changed.
+fn changed_fields<T: Serialize>(
+    old: &T,
+    new: &T,
+    children: &str,
+) -> Option<serde_json::Map<String, serde_json::Value>> {
+    let (old, new) = match (
+        serde_json::to_value(old).ok()?,
+        serde_json::to_value(new).ok()?,
+    ) {
+        (serde_json::Value::Object(old), serde_json::Value::Object(new)) => (old, new),
+        _ => return None,
+    };
+    let mut changed = new
+        .iter()
+        .filter(|(k, v)| *k != children && old.get(*k) != Some(v))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect::<serde_json::Map<String, serde_json::Value>>();
+    old.keys()
+        .filter(|k| *k != children && !new.contains_key(*k))
+        .for_each(|k| {
+            changed.insert(k.clone(), serde_json::Value::Null);
+        });
+    if changed.is_empty() {
+        return None;
+    }
+    changed.insert(String::from("meta"), new.get("meta")?.clone());
+    Some(changed)
+}
+
+///Mark the entity as deleted and let the application know
+fn delete_handler(
+    deleted: &Arc<AtomicBool>,
+    hook: &DeleteHook,
+    changes: &Changes,
+    deletion: Deleted,
+) -> RequestHandler {
+    let deleted = Arc::clone(deleted);
+    let hook = Arc::clone(hook);
+    let changes = changes.clone();
+    RequestHandler::Delete(Arc::new(Mutex::new(Arc::new(move || {
+        deleted.store(true, Ordering::SeqCst);
+        changes.notify();
+        hook.lock().unwrap()(deletion.clone())
+    }))))
+}
+
 #[allow(clippy::from_over_into)]
-impl<C, St, Se> Into<Schema> for &mut InnerNetwork<C, St, Se>
+impl<C, St, Se> Into<Schema> for &InnerNetwork<C, St, Se>
 where
     C: Connect<Se>,
-    St: Store + Default,
+    St: Store,
     Se: WrappedSend,
 {
     fn into(self) -> Schema {
         let mut schema = Schema::new(&self.name, self.id);
         schema.device = self
             .devices
-            .iter()
-            .map(|(_, device)| Device::clone(device).into())
+            .values()
+            .map(|device| Device::clone(device).into())
             .collect();
         schema
     }
 }
 
+///Describe a device on a network. If the network already has a device of the same name, its
+///metadata is replaced.
+///# Example
+///```no_run
+/// # use wappsto_iot_rs::network::*;
+/// # use std::error::Error;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+///     let network: Network = Network::new("my network")?;
+///     let device = network
+///         .device_builder("thermostat")
+///         .manufacturer("ACME")
+///         .product("Thermostat 3000")
+///         .serial("A-1234")
+///         .protocol("Zigbee")
+///         .create();
+/// #   Ok(())
+/// # }
+///```
+pub struct DeviceBuilder<'a, C, St, Se>
+where
+    C: Connect<Se>,
+    St: Store,
+    Se: WrappedSend,
+{
+    network: &'a Network<C, St, Se>,
+    name: String,
+    info: DeviceInfo,
+}
+
+impl<'a, C, St, Se> DeviceBuilder<'a, C, St, Se>
+where
+    C: Connect<Se> + 'static,
+    St: Store + 'static,
+    Se: WrappedSend,
+{
+    pub fn new(network: &'a Network<C, St, Se>, name: &str) -> Self {
+        Self {
+            network,
+            name: String::from(name),
+            info: DeviceInfo::default(),
+        }
+    }
+
+    pub fn manufacturer(mut self, manufacturer: &str) -> Self {
+        self.info.manufacturer = Some(String::from(manufacturer));
+        self
+    }
+
+    pub fn product(mut self, product: &str) -> Self {
+        self.info.product = Some(String::from(product));
+        self
+    }
+
+    pub fn version(mut self, version: &str) -> Self {
+        self.info.version = Some(String::from(version));
+        self
+    }
+
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.info.serial = Some(String::from(serial));
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.info.description = Some(String::from(description));
+        self
+    }
+
+    ///The protocol the device is reached by, e.g. `"Zigbee"`
+    pub fn protocol(mut self, protocol: &str) -> Self {
+        self.info.protocol = Some(String::from(protocol));
+        self
+    }
+
+    ///How the device communicates, e.g. `"always"`
+    pub fn communication(mut self, communication: &str) -> Self {
+        self.info.communication = Some(String::from(communication));
+        self
+    }
+
+    ///Whether the device is included in the network
+    pub fn included(mut self, included: bool) -> Self {
+        self.info.included = Some(String::from(if included { "1" } else { "0" }));
+        self
+    }
+
+    pub fn create(self) -> Device<Se> {
+        let device = self
+            .network
+            .inner
+            .write()
+            .unwrap()
+            .create_device(&self.name, self.info);
+        let network = Arc::downgrade(&self.network.inner);
+        device.inner.write().unwrap().save = Arc::new(move || match Weak::upgrade(&network) {
+            Some(network) => network.write().unwrap().save(),
+            None => Ok(()),
+        });
+        device
+    }
+}
+
 pub struct Device<Se: WrappedSend> {
-    pub inner: Rc<RefCell<InnerDevice<Se>>>,
+    pub inner: Arc<RwLock<InnerDevice<Se>>>,
 }
 
 impl<Se: WrappedSend> Device<Se> {
     pub fn new(device: InnerDevice<Se>) -> Self {
         Self {
-            inner: Rc::new(RefCell::new(device)),
+            inner: Arc::new(RwLock::new(device)),
         }
     }
 
+    ///Create a number value from 0 to 1 with the given permission. Use
+    ///[`value_builder`](Self::value_builder) to configure anything else. A value of the same name
+    ///loaded from the store keeps the type and metadata it was saved with.
     pub fn create_value(&self, name: &str, permission: ValuePermission) -> Value<Se> {
-        self.inner.borrow_mut().create_value(name, permission)
+        let spec = ValueSpec {
+            permission,
+            ..ValueSpec::new(name)
+        };
+        self.inner.write().unwrap().create_value(spec)
+    }
+
+    pub fn value_builder(&self, name: &str) -> ValueBuilder<'_, Se, NoPermission> {
+        ValueBuilder::new(self, name)
+    }
+
+    ///Delete a value, both locally and in Wappsto
+    pub fn delete_value(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.inner.write().unwrap().delete_value(name)?;
+        let save = Arc::clone(&self.inner.read().unwrap().save);
+        save()
     }
 
     #[cfg(test)]
     pub fn value_named(&self, name: &str) -> Option<Value<Se>> {
-        self.inner.borrow().value_named(name).cloned()
+        self.inner.read().unwrap().value_named(name).cloned()
     }
 }
 
 impl<Se: WrappedSend> Clone for Device<Se> {
     fn clone(&self) -> Self {
         Self {
-            inner: Rc::clone(&self.inner),
+            inner: Arc::clone(&self.inner),
         }
     }
 }
 
-impl<Se: WrappedSend> From<Ref<'_, InnerDevice<Se>>> for DeviceSchema {
-    fn from(device: Ref<InnerDevice<Se>>) -> Self {
+impl<Se: WrappedSend> From<RwLockReadGuard<'_, InnerDevice<Se>>> for DeviceSchema {
+    fn from(device: RwLockReadGuard<InnerDevice<Se>>) -> Self {
         let mut device_schema = DeviceSchema::new(&device.name, device.id);
-        device_schema.value = device
-            .values
-            .iter()
-            .map(|(_, value)| ValueSchema::from(value))
-            .collect();
+        device_schema.info = device.info.clone();
+        device_schema.value = device.values.values().map(ValueSchema::from).collect();
         device_schema
     }
 }
 
 impl<Se: WrappedSend> From<Device<Se>> for DeviceSchema {
     fn from(device: Device<Se>) -> Self {
-        Self::from(device.inner.borrow())
+        Self::from(device.inner.read().unwrap())
     }
 }
 
@@ -313,30 +802,212 @@ impl<Se: WrappedSend> Default for Device<Se> {
 pub struct InnerDevice<Se: WrappedSend> {
     pub name: String,
     pub id: Uuid,
+    pub info: DeviceInfo,
     values: HashMap<String, Value<Se>>,
     pub send: Arc<Mutex<Option<Se>>>,
+    pub queue: Arc<Mutex<MessageQueue>>,
+    callbacks: CallbackRegistry,
+    delete_hook: DeleteHook,
+    pending: Pending,
+    save: SaveHook,
+    changes: Changes,
+    deleted: Arc<AtomicBool>,
 }
 
+///Saves the network a device belongs to
+type SaveHook = Arc<dyn Fn() -> Result<(), Box<dyn Error>> + Send + Sync>;
+
 impl<Se: WrappedSend> InnerDevice<Se> {
-    pub fn new(name: &str, id: Uuid, send: Arc<Mutex<Option<Se>>>) -> Self {
+    pub fn new(
+        name: &str,
+        id: Uuid,
+        send: Arc<Mutex<Option<Se>>>,
+        queue: Arc<Mutex<MessageQueue>>,
+        callbacks: CallbackRegistry,
+    ) -> Self {
         Self {
             name: String::from(name),
             id,
+            info: DeviceInfo::default(),
             values: HashMap::new(),
             send,
+            queue,
+            callbacks,
+            delete_hook: default_delete_hook(),
+            pending: Pending::default(),
+            save: Arc::new(|| Ok(())),
+            changes: Changes::default(),
+            deleted: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    #[allow(clippy::mut_from_ref)]
-    pub fn create_value(&mut self, name: &str, permission: ValuePermission) -> Value<Se> {
+    pub fn delete_value(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.prune();
         let value = self
             .values
-            .entry(String::from(name))
-            .and_modify(|v| v.inner.lock().unwrap().send = Arc::clone(&self.send))
-            .or_insert_with(|| {
-                Value::new(InnerValue::new(name, permission, Arc::clone(&self.send)))
-            });
-        Value::clone(value)
+            .remove(name)
+            .ok_or_else(|| format!("No value named {}", name))?;
+        let value = value.inner.lock().unwrap();
+        unregister(&self.callbacks, value.registered_ids());
+        send_or_queue(
+            &self.send,
+            &self.queue,
+            serde_json::to_string(
+                &RpcRequest::builder()
+                    .method(RpcMethod::Delete)
+                    .on_type(RpcType::Value(value.id))
+                    .create(),
+            )?,
+        );
+        Ok(())
+    }
+
+    ///The ids of the device, its values and their states
+    fn registered_ids(&self) -> Vec<Uuid> {
+        self.values
+            .values()
+            .flat_map(|v| v.inner.lock().unwrap().registered_ids())
+            .chain(iter::once(self.id))
+            .collect()
+    }
+
+    ///Register the handlers of requests for the device and its values
+    fn register(&self, callbacks: &mut CallbackMap) {
+        callbacks.insert(
+            self.id,
+            delete_handler(
+                &self.deleted,
+                &self.delete_hook,
+                &self.changes,
+                Deleted::Device {
+                    id: self.id,
+                    name: self.name.clone(),
+                },
+            ),
+        );
+        self.values
+            .values()
+            .for_each(|v| self.register_value(&v.inner.lock().unwrap(), callbacks));
+    }
+
+    fn register_value(&self, value: &InnerValue<Se>, callbacks: &mut CallbackMap) {
+        callbacks.insert(
+            value.id,
+            delete_handler(
+                &value.deleted,
+                &self.delete_hook,
+                &self.changes,
+                Deleted::Value {
+                    id: value.id,
+                    name: value.name.clone(),
+                    device: self.name.clone(),
+                },
+            ),
+        );
+        if let Some(c) = value.control.as_ref() {
+            callbacks.insert(
+                c.inner.id,
+                RequestHandler::Control(Arc::clone(&c.inner.callback)),
+            );
+        }
+        if let Some(r) = value.report.as_ref() {
+            callbacks.insert(r.id, RequestHandler::Refresh(Arc::clone(&r.refresh)));
+        }
+    }
+
+    ///Remove the values deleted in Wappsto. Returns whether anything was removed.
+    fn prune(&mut self) -> bool {
+        let values = self.values.len();
+        let callbacks = &self.callbacks;
+        self.values.retain(|_, v| {
+            let value = v.inner.lock().unwrap();
+            let deleted = value.deleted.load(Ordering::SeqCst);
+            if deleted {
+                unregister(callbacks, value.registered_ids());
+            }
+            !deleted
+        });
+        values != self.values.len()
+    }
+
+    ///Create the value described by `spec`. A value of the same name, e.g. one loaded from the
+    ///store, keeps its id and state ids but takes on the new permission. Its type and metadata
+    ///are only replaced where `spec` gives them.
+    ///The type a value created from `spec` will have
+    fn value_type_of(&self, spec: &ValueSpec) -> ValueType {
+        match (&spec.value_type, self.values.get(&spec.name)) {
+            (Some(value_type), _) => value_type.clone(),
+            (None, Some(value)) => value.inner.lock().unwrap().value_type.clone(),
+            (None, None) => ValueType::default(),
+        }
+    }
+
+    pub(crate) fn create_value(&mut self, spec: ValueSpec) -> Value<Se> {
+        self.prune();
+        let old = self.values.get(&spec.name).map(ValueSchema::from);
+        let value = match self.values.get(&spec.name) {
+            Some(value) => {
+                let mut inner = value.inner.lock().unwrap();
+                unregister(&self.callbacks, inner.registered_ids());
+                inner.send = Arc::clone(&self.send);
+                inner.queue = Arc::clone(&self.queue);
+                inner.pending = self.pending.clone();
+                inner.changes = self.changes.clone();
+                inner.update(spec);
+                Value::clone(value)
+            }
+            None => {
+                let name = spec.name.clone();
+                let mut inner = InnerValue::new(
+                    &name,
+                    ValuePermission::R,
+                    ValueType::default(),
+                    Arc::clone(&self.send),
+                    Arc::clone(&self.queue),
+                );
+                inner.pending = self.pending.clone();
+                inner.changes = self.changes.clone();
+                inner.update(spec);
+                let value = Value::new(inner);
+                self.values.insert(name, Value::clone(&value));
+                value
+            }
+        };
+        self.register_value(
+            &value.inner.lock().unwrap(),
+            &mut self.callbacks.lock().unwrap(),
+        );
+        self.publish_value(old, ValueSchema::from(&value));
+        self.changes.notify();
+        value
+    }
+
+    ///Let Wappsto know about a value created or changed after the network was started. New
+    ///values, and values whose states changed, are posted whole; otherwise only the fields that
+    ///changed are sent.
+    fn publish_value(&self, old: Option<ValueSchema>, new: ValueSchema) {
+        let states = |v: &ValueSchema| {
+            v.state
+                .iter()
+                .map(|s| (s.meta.id, s.state_type))
+                .collect::<Vec<(Uuid, StateType)>>()
+        };
+        let request = match old {
+            Some(old) if states(&old) == states(&new) => {
+                match changed_fields(&old, &new, "state") {
+                    Some(changed) => RpcRequest::builder()
+                        .method(RpcMethod::Put)
+                        .on_type(RpcType::Value(new.meta.id))
+                        .data(RpcData::Partial(changed)),
+                    None => return,
+                }
+            }
+            _ => RpcRequest::builder()
+                .method(RpcMethod::Post)
+                .on_type(RpcType::DeviceValues(self.id))
+                .data(RpcData::Value(new)),
+        };
+        send_if_started(&self.send, &self.queue, request.create())
     }
 
     #[cfg(test)]
@@ -347,13 +1018,26 @@ impl<Se: WrappedSend> InnerDevice<Se> {
 
 impl<Se: WrappedSend> Default for InnerDevice<Se> {
     fn default() -> Self {
-        Self::new("", Uuid::new_v4(), Arc::new(Mutex::new(None)))
+        Self::new(
+            "",
+            Uuid::new_v4(),
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(MessageQueue::default())),
+            Arc::default(),
+        )
     }
 }
 
 impl<Se: WrappedSend> From<DeviceSchema> for InnerDevice<Se> {
     fn from(schema: DeviceSchema) -> Self {
-        let mut device = InnerDevice::new(&schema.name, schema.meta.id, Arc::new(Mutex::new(None)));
+        let mut device = InnerDevice::new(
+            &schema.name,
+            schema.meta.id,
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(MessageQueue::default())),
+            Arc::default(),
+        );
+        device.info = schema.info;
         device.values = schema
             .value
             .into_iter()
@@ -366,15 +1050,148 @@ impl<Se: WrappedSend> From<DeviceSchema> for InnerDevice<Se> {
 impl<Se: WrappedSend> From<&InnerDevice<Se>> for DeviceSchema {
     fn from(device: &InnerDevice<Se>) -> Self {
         let mut device_schema = DeviceSchema::new(&device.name, device.id);
-        device_schema.value = device
-            .values
-            .iter()
-            .map(|(_, value)| ValueSchema::from(value))
-            .collect();
+        device_schema.info = device.info.clone();
+        device_schema.value = device.values.values().map(ValueSchema::from).collect();
         device_schema
     }
 }
 
+#[doc(hidden)]
+pub struct NoPermission;
+
+#[doc(hidden)]
+pub struct WithPermission;
+
+#[doc(hidden)]
+pub trait PermissionState {}
+
+impl PermissionState for NoPermission {}
+impl PermissionState for WithPermission {}
+
+///Describe a value on a device. A permission must be given before the value can be created. If
+///the device already has a value of the same name, it is updated to match.
+///# Example
+///```no_run
+/// # use wappsto_iot_rs::network::*;
+/// # use wappsto_iot_rs::schema::*;
+/// # use std::error::Error;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+///     let network: Network = Network::new("my network")?;
+///     let device = network.create_device("thermostat");
+///     let temperature = device
+///         .value_builder("temperature")
+///         .permission(ValuePermission::R)
+///         .value_type(ValueType::Number(NumberSchema::new(-40f64, 125f64, 0.1, "°C")))
+///         .type_name("temperature")
+///         .period("60")
+///         .delta("0.5")
+///         .initial_data("20")
+///         .create()?;
+/// #   Ok(())
+/// # }
+///```
+pub struct ValueBuilder<'a, Se: WrappedSend, P: PermissionState> {
+    device: &'a Device<Se>,
+    spec: ValueSpec,
+    permission_state: PhantomData<P>,
+}
+
+impl<'a, Se: WrappedSend> ValueBuilder<'a, Se, NoPermission> {
+    pub fn new(device: &'a Device<Se>, name: &str) -> Self {
+        Self {
+            device,
+            spec: ValueSpec::new(name),
+            permission_state: PhantomData,
+        }
+    }
+}
+
+impl<'a, Se: WrappedSend, P: PermissionState> ValueBuilder<'a, Se, P> {
+    pub fn permission(self, permission: ValuePermission) -> ValueBuilder<'a, Se, WithPermission> {
+        ValueBuilder {
+            device: self.device,
+            spec: ValueSpec {
+                permission,
+                ..self.spec
+            },
+            permission_state: PhantomData,
+        }
+    }
+
+    ///The kind of data the value holds. Defaults to a number from 0 to 1, or to the type a value
+    ///of the same name was saved with.
+    pub fn value_type(mut self, value_type: ValueType) -> Self {
+        self.spec.value_type = Some(value_type);
+        self
+    }
+
+    ///What the value measures or controls, e.g. `"temperature"` or `"switch"`
+    pub fn type_name(mut self, type_name: &str) -> Self {
+        self.spec.type_name = Some(String::from(type_name));
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.spec.description = Some(String::from(description));
+        self
+    }
+
+    ///How often the value is reported, in seconds
+    pub fn period(mut self, period: &str) -> Self {
+        self.spec.period = Some(String::from(period));
+        self
+    }
+
+    ///How much the value must change before it is reported
+    pub fn delta(mut self, delta: &str) -> Self {
+        self.spec.delta = Some(String::from(delta));
+        self
+    }
+
+    ///The data of the report state when the value is first published
+    pub fn initial_data(mut self, data: &str) -> Self {
+        self.spec.initial_data = Some(String::from(data));
+        self
+    }
+}
+
+impl<'a, Se: WrappedSend> ValueBuilder<'a, Se, WithPermission> {
+    ///Create the value, or fail if the initial data does not match its type
+    pub fn create(self) -> Result<Value<Se>, ValidationError> {
+        let mut device = self.device.inner.write().unwrap();
+        if let Some(data) = self.spec.initial_data.as_ref() {
+            device.value_type_of(&self.spec).validate(data)?;
+        }
+        Ok(device.create_value(self.spec))
+    }
+}
+
+pub(crate) struct ValueSpec {
+    name: String,
+    permission: ValuePermission,
+    value_type: Option<ValueType>,
+    type_name: Option<String>,
+    description: Option<String>,
+    period: Option<String>,
+    delta: Option<String>,
+    initial_data: Option<String>,
+}
+
+impl ValueSpec {
+    fn new(name: &str) -> Self {
+        Self {
+            name: String::from(name),
+            permission: ValuePermission::R,
+            value_type: None,
+            type_name: None,
+            description: None,
+            period: None,
+            delta: None,
+            initial_data: None,
+        }
+    }
+}
+
 pub struct Value<Se: WrappedSend> {
     pub inner: Arc<Mutex<InnerValue<Se>>>,
 }
@@ -389,17 +1206,105 @@ impl<Se: WrappedSend> Clone for Value<Se> {
 
 impl<Se: WrappedSend> Value<Se> {
     pub fn new(value: InnerValue<Se>) -> Self {
-        Self {
-            inner: Arc::new(Mutex::new(value)),
-        }
+        let inner = Arc::new(Mutex::new(value));
+        let value = Arc::downgrade(&inner);
+        *inner.lock().unwrap().refresh.lock().unwrap() = Arc::new(move || match value.upgrade() {
+            Some(value) => value.lock().unwrap().report_last(),
+            None => Ok(()),
+        });
+        Self { inner }
     }
 
-    pub fn report(&self, data: &str) {
+    ///Report a new state to Wappsto. The returned [`Ack`] tells whether Wappsto received it.
+    pub fn report(&self, data: &str) -> Result<Ack, Box<dyn Error>> {
         self.inner.lock().unwrap().report(data)
     }
 
-    pub fn on_control(&self, callback: Box<dyn Fn(String) + Send + Sync>) {
-        self.inner.lock().unwrap().on_control(callback)
+    ///Report a number, boolean, string or byte blob, e.g. `value.report_typed(21.5)`. Numbers are
+    ///rounded to the step of the value before they are checked against its range.
+    pub fn report_typed<T: IntoData>(&self, data: T) -> Result<Ack, Box<dyn Error>> {
+        self.inner.lock().unwrap().report_typed(data)
+    }
+
+    ///Report data that was measured at `timestamp` rather than now
+    pub fn report_at(&self, data: &str, timestamp: DateTime<Utc>) -> Result<Ack, Box<dyn Error>> {
+        self.inner.lock().unwrap().report_at(data, timestamp)
+    }
+
+    ///Upload a series of historical samples. The samples are sent in timestamp order as a single
+    ///message, so none of them are dropped when reports are coalesced while offline, and the
+    ///message is queued until the network is connected like any report. Nothing is sent if any
+    ///sample is invalid. The returned [`Ack`]s are in timestamp order as well.
+    pub fn report_history<T: IntoData>(
+        &self,
+        samples: impl IntoIterator<Item = (DateTime<Utc>, T)>,
+    ) -> Result<Vec<Ack>, Box<dyn Error>> {
+        self.inner.lock().unwrap().report_history(samples)
+    }
+
+    ///Replace the handler of controls from Wappsto. The data is parsed as `T` according to the
+    ///type of the value, e.g. `value.on_control::<f64>(..)`, and invalid data is rejected
+    ///without calling the handler. Values without a control state are never controlled.
+    pub fn on_control<T: FromData>(&self, callback: impl Fn(T) + Send + Sync + 'static) {
+        let control = self.inner.lock().unwrap().control_callback(callback);
+        if let Some((handler, callback)) = control {
+            *handler.lock().unwrap() = callback
+        }
+    }
+
+    ///Report a new state to Wappsto, and wait for Wappsto to acknowledge it without blocking the
+    ///runtime
+    #[cfg(feature = "async")]
+    pub async fn report_async<T: IntoData>(
+        &self,
+        data: T,
+    ) -> Result<serde_json::Value, Box<dyn Error>> {
+        let ack = self.inner.lock().unwrap().report_async(data)?;
+        Ok(ack.wait().await?)
+    }
+
+    ///Like [`on_control`](Self::on_control), but the handler is async. Each control is handled by
+    ///a task spawned on the tokio runtime this is called from, so it fails outside of one.
+    #[cfg(feature = "async")]
+    pub fn on_control_async<T, F, Fut>(&self, callback: F) -> Result<(), Box<dyn Error>>
+    where
+        T: FromData,
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let runtime = tokio::runtime::Handle::try_current()?;
+        self.on_control(move |data: T| {
+            runtime.spawn(callback(data));
+        });
+        Ok(())
+    }
+
+    ///The data last reported, e.g. before the network was restarted. None if nothing has been
+    ///reported yet.
+    pub fn last_report(&self) -> Option<StateData> {
+        self.inner.lock().unwrap().last_report()
+    }
+
+    ///The data of the last valid control from Wappsto, e.g. to restore an actuator after a
+    ///restart. None if the value has never been controlled.
+    pub fn last_control(&self) -> Option<StateData> {
+        self.inner.lock().unwrap().last_control()
+    }
+
+    ///Register a handler for requests from Wappsto for a fresh reading. What the handler returns
+    ///is reported like [`report_typed`](Self::report_typed). Values without a report state are
+    ///never refreshed. Without a handler, the last report is sent again, and the request is
+    ///answered with an error if there is none.
+    pub fn on_refresh<T: IntoData>(&self, callback: impl Fn() -> T + Send + Sync + 'static) {
+        let value = Arc::downgrade(&self.inner);
+        let handler = Arc::clone(&self.inner.lock().unwrap().refresh);
+        *handler.lock().unwrap() = Arc::new(move || {
+            let data = callback();
+            match value.upgrade() {
+                Some(value) => value.lock().unwrap().report_typed(data).map(|_| ()),
+                None => Ok(()),
+            }
+        });
     }
 
     #[cfg(test)]
@@ -412,15 +1317,12 @@ impl<Se: WrappedSend> Value<Se> {
             .unwrap()
             .inner
             .id
-            .clone()
     }
 }
 
 impl<Se: WrappedSend> From<ValueSchema> for Value<Se> {
     fn from(schema: ValueSchema) -> Self {
-        Self {
-            inner: Arc::new(Mutex::new(InnerValue::from(schema))),
-        }
+        Self::new(InnerValue::from(schema))
     }
 }
 
@@ -434,103 +1336,331 @@ pub struct InnerValue<Se: WrappedSend> {
     name: String,
     id: Uuid,
     permission: ValuePermission,
+    value_type: ValueType,
+    type_name: Option<String>,
+    description: Option<String>,
+    period: Option<String>,
+    delta: Option<String>,
     pub send: Arc<Mutex<Option<Se>>>,
+    pub queue: Arc<Mutex<MessageQueue>>,
+    pending: Pending,
     pub control: Option<ControlState>,
     pub report: Option<InnerReportState>,
+    refresh: RefreshHandler,
+    changes: Changes,
+    deleted: Arc<AtomicBool>,
 }
 
 impl<Se: WrappedSend> InnerValue<Se> {
-    pub fn new(name: &str, permission: ValuePermission, send: Arc<Mutex<Option<Se>>>) -> Self {
-        Self::new_with_id(name, permission, Uuid::new_v4(), send)
+    pub fn new(
+        name: &str,
+        permission: ValuePermission,
+        value_type: ValueType,
+        send: Arc<Mutex<Option<Se>>>,
+        queue: Arc<Mutex<MessageQueue>>,
+    ) -> Self {
+        Self::new_with_id(name, permission, value_type, Uuid::new_v4(), send, queue)
     }
 
     pub fn new_with_id(
         name: &str,
         permission: ValuePermission,
+        value_type: ValueType,
         id: Uuid,
         send: Arc<Mutex<Option<Se>>>,
+        queue: Arc<Mutex<MessageQueue>>,
     ) -> Self {
-        let permission_record = match &permission {
+        let mut value = Self {
+            name: String::from(name),
+            id,
+            permission: ValuePermission::R,
+            value_type,
+            type_name: None,
+            description: None,
+            period: None,
+            delta: None,
+            report: None,
+            control: None,
+            refresh: Arc::new(Mutex::new(Arc::new(|| {
+                Err("Nothing has been reported yet".into())
+            }))),
+            send,
+            queue,
+            pending: Pending::default(),
+            changes: Changes::default(),
+            deleted: Arc::new(AtomicBool::new(false)),
+        };
+        value.set_permission(permission);
+        value
+    }
+
+    ///Change the permission, keeping the ids of states that are still needed. A control callback
+    ///is swapped in place, so it stays registered with a running connection.
+    fn set_permission(&mut self, permission: ValuePermission) {
+        self.permission = match &permission {
             ValuePermission::R => ValuePermission::R,
             ValuePermission::RW(_) => ValuePermission::RW(Box::new(|_| {})),
             ValuePermission::W(_) => ValuePermission::W(Box::new(|_| {})),
         };
-        let (report, control) = match permission {
-            ValuePermission::RW(f) => (
-                Some(InnerReportState::new(Uuid::new_v4())),
-                Some(ControlState::new(InnerControlState::new(
-                    Uuid::new_v4(),
-                    Arc::new(Mutex::new(f)),
-                ))),
-            ),
-            ValuePermission::R => (Some(InnerReportState::new(Uuid::new_v4())), None),
-            ValuePermission::W(f) => (
-                None,
-                Some(ControlState::new(InnerControlState::new(
-                    Uuid::new_v4(),
-                    Arc::new(Mutex::new(f)),
-                ))),
-            ),
+        let (report, callback) = match permission {
+            ValuePermission::RW(f) => (true, Some(f)),
+            ValuePermission::R => (true, None),
+            ValuePermission::W(f) => (false, Some(f)),
+        };
+        if !report {
+            self.report = None
+        } else if self.report.is_none() {
+            self.report = Some(InnerReportState::new(
+                Uuid::new_v4(),
+                Arc::clone(&self.refresh),
+            ))
+        }
+        let last = match self.control.as_ref() {
+            Some(control) => Arc::clone(&control.last),
+            None => Arc::default(),
+        };
+        let handler = callback.map(|f| self.control_handler(&last, f));
+        self.control = match (handler, self.control.take()) {
+            (Some(f), Some(control)) => {
+                *control.callback.lock().unwrap() = f;
+                Some(control)
+            }
+            (Some(f), None) => Some(ControlState::new(InnerControlState::new(
+                Uuid::new_v4(),
+                Arc::new(Mutex::new(f)),
+                last,
+            ))),
+            (None, _) => None,
         };
+    }
 
-        Self {
-            name: String::from(name),
-            id,
-            permission: permission_record,
-            report,
-            control,
-            send,
+    ///Take on the permission of `spec`, and whatever type and metadata it gives
+    pub(crate) fn update(&mut self, spec: ValueSpec) {
+        if let Some(value_type) = spec.value_type {
+            self.value_type = value_type;
+        }
+        self.set_permission(spec.permission);
+        self.type_name = spec.type_name.or(self.type_name.take());
+        self.description = spec.description.or(self.description.take());
+        self.period = spec.period.or(self.period.take());
+        self.delta = spec.delta.or(self.delta.take());
+        if let (Some(report), Some(data)) = (self.report.as_mut(), spec.initial_data) {
+            report
+                .last
+                .get_or_insert_with(|| StateData::new(&data, Utc::now()));
         }
     }
 
-    pub fn report(&self, data: &str) {
-        self.send
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .send(
-                serde_json::to_string(
-                    &RpcRequest::builder()
-                        .method(RpcMethod::Put)
-                        .on_type(RpcType::State)
-                        .data(RpcData::Data(RpcStateData::new(
-                            data,
-                            Utc::now(),
-                            Meta::new_with_uuid(self.report.as_ref().unwrap().id, MetaType::State),
-                        )))
-                        .create(),
-                )
-                .unwrap(),
+    ///Report a new state to Wappsto. If the network is not connected, the report is queued and
+    ///sent once it is. Data that does not match the type of the value is rejected.
+    pub fn report(&mut self, data: &str) -> Result<Ack, Box<dyn Error>> {
+        self.report_at(data, Utc::now())
+    }
+
+    pub fn report_typed<T: IntoData>(&mut self, data: T) -> Result<Ack, Box<dyn Error>> {
+        self.report(&data.into_data(&self.value_type)?)
+    }
+
+    ///Send the last report again, e.g. when Wappsto asks for a fresh reading of a value that has
+    ///no refresh handler
+    fn report_last(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.last_report() {
+            Some(last) => self.report_at(&last.data, last.timestamp).map(|_| ()),
+            None => Err("Nothing has been reported yet".into()),
+        }
+    }
+
+    pub fn report_at(
+        &mut self,
+        data: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Ack, Box<dyn Error>> {
+        let request = self.report_request(data, timestamp)?;
+        self.remember_report(data, timestamp);
+        self.send_tracked(request, Pending::track)
+    }
+
+    #[cfg(feature = "async")]
+    pub fn report_async<T: IntoData>(&mut self, data: T) -> Result<AsyncAck, Box<dyn Error>> {
+        let data = data.into_data(&self.value_type)?;
+        let timestamp = Utc::now();
+        let request = self.report_request(&data, timestamp)?;
+        self.remember_report(&data, timestamp);
+        self.send_tracked(request, AsyncAck::new)
+    }
+
+    ///Keep `data` as the last report, unless a later one is already known, so it is saved and
+    ///published along with the network
+    fn remember_report(&mut self, data: &str, timestamp: DateTime<Utc>) {
+        if let Some(report) = self.report.as_mut() {
+            if report
+                .last
+                .as_ref()
+                .is_none_or(|l| l.timestamp <= timestamp)
+            {
+                report.last = Some(StateData::new(data, timestamp));
+                self.changes.notify_report();
+            }
+        }
+    }
+
+    ///Send a request, waiting for its response with `track`
+    fn send_tracked<A>(
+        &self,
+        request: RpcRequest,
+        track: impl FnOnce(&Pending, &str) -> A,
+    ) -> Result<A, Box<dyn Error>> {
+        let msg = serde_json::to_string(&request)?;
+        let ack = track(&self.pending, &request.id);
+        self.send_or_queue(msg);
+        Ok(ack)
+    }
+
+    pub fn report_history<T: IntoData>(
+        &mut self,
+        samples: impl IntoIterator<Item = (DateTime<Utc>, T)>,
+    ) -> Result<Vec<Ack>, Box<dyn Error>> {
+        let mut samples = samples
+            .into_iter()
+            .map(|(timestamp, data)| Ok((timestamp, data.into_data(&self.value_type)?)))
+            .collect::<Result<Vec<(DateTime<Utc>, String)>, ValidationError>>()?;
+        if samples.is_empty() {
+            return Ok(vec![]);
+        }
+        samples.sort_by_key(|(timestamp, _)| *timestamp);
+        let requests = samples
+            .iter()
+            .map(|(timestamp, data)| self.report_request(data, *timestamp))
+            .collect::<Result<Vec<RpcRequest>, Box<dyn Error>>>()?;
+        let msg = serde_json::to_string(&requests)?;
+        let acks = requests
+            .iter()
+            .map(|request| self.pending.track(&request.id))
+            .collect();
+        self.send_or_queue(msg);
+        if let Some((timestamp, data)) = samples.last() {
+            self.remember_report(data, *timestamp);
+        }
+        Ok(acks)
+    }
+
+    fn report_request(
+        &self,
+        data: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<RpcRequest, Box<dyn Error>> {
+        self.value_type.validate(data)?;
+        let report = self.report.as_ref().ok_or("Value has no report state")?;
+        Ok(RpcRequest::builder()
+            .method(RpcMethod::Put)
+            .on_type(RpcType::State)
+            .data(RpcData::Data(RpcStateData::new(
+                data,
+                timestamp,
+                Meta::new_with_uuid(report.id, MetaType::State),
+            )))
+            .create())
+    }
+
+    fn send_or_queue(&self, msg: String) {
+        send_or_queue(&self.send, &self.queue, msg)
+    }
+
+    ///The ids of the value and its states
+    fn registered_ids(&self) -> Vec<Uuid> {
+        iter::once(self.id)
+            .chain(self.report.as_ref().map(|r| r.id))
+            .chain(self.control.as_ref().map(|c| c.id))
+            .collect()
+    }
+
+    ///The handler of controls, and `callback` wrapped to replace what it holds. The value does not
+    ///have to stay locked while it is replaced.
+    pub fn control_callback<T: FromData>(
+        &self,
+        callback: impl Fn(T) + Send + Sync + 'static,
+    ) -> Option<(ControlHandler, ControlCallback)> {
+        self.control.as_ref().map(|control| {
+            (
+                Arc::clone(&control.callback),
+                self.control_handler(&control.last, callback),
             )
-            .unwrap();
+        })
     }
 
-    pub fn on_control(&self, callback: Box<dyn Fn(String) + Send + Sync>) {
-        *self
-            .control
-            .as_ref()
-            .unwrap()
-            .callback
+    ///Wrap a control callback so it is only called with data that is valid for the type of the
+    ///value. Valid data is kept as the last control.
+    fn control_handler<T: FromData>(
+        &self,
+        last: &Arc<Mutex<Option<StateData>>>,
+        callback: impl Fn(T) + Send + Sync + 'static,
+    ) -> ControlCallback {
+        let value_type = self.value_type.clone();
+        let last = Arc::clone(last);
+        let changes = self.changes.clone();
+        Arc::new(move |data| {
+            let parsed = T::from_data(&data, &value_type)?;
+            last.lock()
+                .unwrap()
+                .replace(StateData::new(&data, Utc::now()));
+            changes.notify();
+            callback(parsed);
+            Ok(())
+        })
+    }
+
+    pub fn last_report(&self) -> Option<StateData> {
+        self.report.as_ref().and_then(|r| r.last.clone())
+    }
+
+    pub fn last_control(&self) -> Option<StateData> {
+        self.control
             .as_ref()
-            .lock()
-            .unwrap() = callback
+            .and_then(|c| c.last.lock().unwrap().clone())
     }
 
     #[cfg(test)]
-    pub fn control(&self, data: String) {
-        (self.control.as_ref().unwrap().callback.lock().unwrap())(data)
+    pub fn control(&self, data: String) -> Result<(), ValidationError> {
+        let callback = Arc::clone(&self.control.as_ref().unwrap().callback.lock().unwrap());
+        callback(data)
     }
 }
 
 impl<Se: WrappedSend> From<ValueSchema> for InnerValue<Se> {
     fn from(schema: ValueSchema) -> Self {
-        Self::new_with_id(
+        let mut value = Self::new_with_id(
             &schema.name,
             ValuePermission::from(schema.permission),
+            schema.value_type,
             schema.meta.id,
             Arc::new(Mutex::new(None)),
-        )
+            Arc::new(Mutex::new(MessageQueue::default())),
+        );
+        value.type_name = schema.type_name;
+        value.description = schema.description;
+        value.period = schema.period;
+        value.delta = schema.delta;
+        for state in schema.state {
+            match state.state_type {
+                StateType::Report => {
+                    if let Some(report) = value.report.as_mut() {
+                        report.id = state.meta.id;
+                        report.last = StateData::from_state(&state);
+                    }
+                }
+                StateType::Control => {
+                    value.control = value.control.take().map(|c| {
+                        *c.last.lock().unwrap() = StateData::from_state(&state);
+                        ControlState::new(InnerControlState::new(
+                            state.meta.id,
+                            Arc::clone(&c.callback),
+                            Arc::clone(&c.last),
+                        ))
+                    })
+                }
+            }
+        }
+        value
     }
 }
 
@@ -539,23 +1669,35 @@ impl<Se: WrappedSend> From<MutexGuard<'_, InnerValue<Se>>> for ValueSchema {
         let permission = &value.permission;
         let permission: Permission = permission.into();
         let mut values_schema =
-            Self::new_with_id(&value.name, permission, NumberSchema::default(), value.id);
+            Self::new_with_id(&value.name, permission, value.value_type.clone(), value.id);
+        values_schema.type_name = value.type_name.clone();
+        values_schema.description = value.description.clone();
+        values_schema.period = value.period.clone();
+        values_schema.delta = value.delta.clone();
         values_schema.state = vec![];
         if let Some(s) = value.report.as_ref() {
-            values_schema
-                .state
-                .push(State::new_with_id(StateType::Report, s.id))
+            values_schema.state.push(StateData::to_state(
+                StateType::Report,
+                s.id,
+                s.last.as_ref(),
+            ))
         };
 
         if let Some(s) = value.control.as_ref() {
-            values_schema
-                .state
-                .push(State::new_with_id(StateType::Control, s.inner.id))
+            values_schema.state.push(StateData::to_state(
+                StateType::Control,
+                s.inner.id,
+                s.last.lock().unwrap().as_ref(),
+            ))
         };
         values_schema
     }
 }
 
+type ControlCallback = Arc<dyn Fn(String) -> Result<(), ValidationError> + Send + Sync>;
+
+///The permission of a value. Control callbacks are only called with data that is valid for the
+///type of the value.
 pub enum ValuePermission {
     RW(Box<dyn Fn(String) + Send + Sync>),
     R,
@@ -611,20 +1753,20 @@ impl Deref for ControlState {
     }
 }
 
-#[allow(clippy::type_complexity)]
 pub struct InnerControlState {
     pub id: Uuid,
-    pub callback: Arc<Mutex<Box<dyn Fn(String) + Send + Sync>>>,
+    pub callback: ControlHandler,
+    last: Arc<Mutex<Option<StateData>>>,
 }
 
 pub struct ReportState {
-    inner: Rc<InnerReportState>,
+    inner: Arc<InnerReportState>,
 }
 
 impl ReportState {
     pub fn new(report_state: InnerReportState) -> Self {
         Self {
-            inner: Rc::new(report_state),
+            inner: Arc::new(report_state),
         }
     }
 }
@@ -638,17 +1780,57 @@ impl Deref for ReportState {
 
 pub struct InnerReportState {
     pub id: Uuid,
+    last: Option<StateData>,
+    pub refresh: RefreshHandler,
 }
 
 impl InnerControlState {
-    #[allow(clippy::type_complexity)]
-    pub fn new(id: Uuid, callback: Arc<Mutex<Box<dyn Fn(String) + Send + Sync>>>) -> Self {
-        Self { id, callback }
+    pub fn new(id: Uuid, callback: ControlHandler, last: Arc<Mutex<Option<StateData>>>) -> Self {
+        Self { id, callback, last }
     }
 }
 
 impl InnerReportState {
-    pub fn new(id: Uuid) -> Self {
-        Self { id }
+    pub fn new(id: Uuid, refresh: RefreshHandler) -> Self {
+        Self {
+            id,
+            last: None,
+            refresh,
+        }
+    }
+}
+
+///The data last reported or received on a state, and when
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateData {
+    pub data: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl StateData {
+    pub fn new(data: &str, timestamp: DateTime<Utc>) -> Self {
+        Self {
+            data: String::from(data),
+            timestamp,
+        }
+    }
+
+    ///The data of a saved state, if it has any
+    fn from_state(state: &State) -> Option<Self> {
+        if state.data.is_empty() {
+            return None;
+        }
+        let timestamp = DateTime::parse_from_rfc3339(&state.timestamp)
+            .map(|t| t.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now());
+        Some(Self::new(&state.data, timestamp))
+    }
+
+    fn to_state(state_type: StateType, id: Uuid, last: Option<&Self>) -> State {
+        let state = State::new_with_id(state_type, id);
+        match last {
+            Some(last) => state.with_data(&last.data).at(last.timestamp),
+            None => state,
+        }
     }
 }
@@ -1,20 +1,22 @@
 mod network {
     use std::{
         str::FromStr,
-        sync::{Arc, Mutex},
-        thread::sleep,
+        sync::{mpsc, Arc, Mutex},
+        thread::{self, sleep},
         time::Duration,
     };
 
-    use chrono::Utc;
     use uuid::Uuid;
 
     use crate::{
+        autosave::Autosave,
+        communication::{AckError, CommunicationError},
+        connection::SendChannel,
         fs_store::Store,
-        network::{Network, ValuePermission},
+        network::{Deleted, Device, Network, Value, ValuePermission},
         network_test::{connection::WrappedSendMock, store::StoreMock},
-        rpc::{RpcData, RpcMethod, RpcRequest, RpcStateData},
-        schema::{DeviceSchema, Meta, MetaType, Schema},
+        schema::{DeviceSchema, NumberSchema, Schema, ValidationError, ValueSchema, ValueType},
+        test_support::control_state_rpc,
     };
 
     use super::{connection::ConnectionMock, store::DEFAULT_ID};
@@ -31,7 +33,7 @@ mod network {
         let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
             Network::new("test").unwrap();
         network.start().expect("Failed to start");
-        assert!(*network.connection().is_started.borrow());
+        assert!(*network.connection().is_started.lock().unwrap());
     }
 
     #[test]
@@ -54,20 +56,95 @@ mod network {
                 .store()
                 .load_schema(Uuid::parse_str(DEFAULT_ID).unwrap())
                 .unwrap()
+                .unwrap()
                 .meta
                 .id
         )
     }
 
+    #[test]
+    fn should_close_connection_on_stop() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.start().unwrap();
+        network.stop().unwrap();
+        assert!(*network.connection().was_closed.lock().unwrap());
+        assert!(network
+            .connection()
+            .stream
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .is_shut_down());
+    }
+
+    #[test]
+    fn should_let_handlers_use_network_while_stopping() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let (controlled, was_controlled) = mpsc::channel();
+        let handled_network = network.clone();
+        let state_id = network
+            .create_device("test_device")
+            .create_value(
+                "test_value",
+                ValuePermission::RW(Box::new(move |_| {
+                    controlled.send(()).unwrap();
+                    sleep(Duration::from_millis(50));
+                    handled_network.create_device("hot_plugged");
+                })),
+            )
+            .control_id();
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+
+        stream.receive(&control_state_rpc("1", state_id));
+        was_controlled.recv_timeout(Duration::from_secs(1)).unwrap();
+        let (stopped, was_stopped) = mpsc::channel();
+        let stopping_network = network.clone();
+        thread::spawn(move || stopped.send(stopping_network.stop().is_ok()).unwrap());
+
+        assert!(was_stopped.recv_timeout(Duration::from_secs(2)).unwrap());
+        assert!(network.device_named("hot_plugged").is_some());
+    }
+
+    #[test]
+    fn should_publish_current_network_after_reconnect() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.create_device("deleted_device");
+        network.start().unwrap();
+        network.delete_device("deleted_device").unwrap();
+        network.create_device("new_device");
+
+        let schema = network.connection().schema.lock().unwrap().take().unwrap();
+        let published = schema().unwrap();
+        assert!(published.contains("new_device"));
+        assert!(!published.contains("deleted_device"));
+    }
+
+    #[test]
+    fn should_publish_again_when_restarted() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        network.stop().unwrap();
+        network.start().unwrap();
+        network.stop().unwrap();
+        assert_eq!(2, stream.sent().matches(&network.id().to_string()).count());
+    }
+
     #[test]
     fn should_load_schema_from_store_on_creation() {
-        let mut schema = Schema::new("test", Uuid::from_str(&DEFAULT_ID).unwrap());
+        let mut schema = Schema::new("test", Uuid::from_str(DEFAULT_ID).unwrap());
         let device = DeviceSchema::new("test_device", Uuid::new_v4());
         schema.device.push(device);
         let store = StoreMock::default();
         store.save_schema(schema).unwrap();
         let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
-            Network::new_with_store("test", store);
+            Network::with_store("test", store).unwrap();
         assert!(!network.devices_is_empty());
         assert!(network.device_named("test_device").is_some())
     }
@@ -86,14 +163,19 @@ mod network {
         let store = StoreMock::default();
         let mut schema = Schema::new("test", Uuid::parse_str(DEFAULT_ID).unwrap());
         let device = DeviceSchema::new("test_device", Uuid::new_v4());
-        let device_id = device.meta.id.clone();
+        let device_id = device.meta.id;
         schema.device.push(device);
         store.save_schema(schema).unwrap();
         let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
-            Network::new_with_store("test", store);
+            Network::with_store("test", store).unwrap();
         assert_eq!(
             device_id,
-            network.create_device("test_device").inner.borrow().id
+            network
+                .create_device("test_device")
+                .inner
+                .read()
+                .unwrap()
+                .id
         )
     }
 
@@ -113,7 +195,8 @@ mod network {
         sleep(Duration::from_millis(50));
         assert!(network
             .inner
-            .borrow()
+            .read()
+            .unwrap()
             .send
             .lock()
             .unwrap()
@@ -123,202 +206,1529 @@ mod network {
     }
 
     #[test]
-    fn should_pass_callbacks_to_reader() {
-        let callback_was_called = Arc::new(Mutex::new(false));
-        let callback_was_called_sent = Arc::clone(&callback_was_called);
-        let callback = move |_: String| {
-            *callback_was_called_sent.lock().unwrap() = true;
-        };
+    fn should_publish_number_details() {
         let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
             Network::new("test").unwrap();
         let device = network.create_device("test_device");
-        let state_id = device
-            .create_value("test_value", ValuePermission::RW(Box::new(callback)))
-            .control_id();
+        device
+            .value_builder("temperature")
+            .permission(ValuePermission::R)
+            .value_type(ValueType::Number(
+                NumberSchema::new(-20f64, 50f64, 0.5, "°C").si_conversion("[K] = [°C] + 273.15"),
+            ))
+            .create()
+            .unwrap();
+        network.start().unwrap();
+        let sent = network.connection().stream.lock().unwrap().clone().unwrap();
+        sleep(Duration::from_millis(50));
+        assert!(sent.sent().contains("[K] = [°C] + 273.15"));
+    }
 
+    #[test]
+    fn should_publish_device_metadata() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
         network
-            .connection()
-            .stream
-            .borrow_mut()
-            .as_mut()
-            .unwrap()
-            .receive(&control_state_rpc("1", state_id));
+            .device_builder("test_device")
+            .manufacturer("ACME")
+            .product("Thermostat")
+            .version("1.2")
+            .serial("A-1")
+            .protocol("Zigbee")
+            .communication("always")
+            .included(true)
+            .create();
         network.start().unwrap();
-        network
+        let sent = network.connection().stream.lock().unwrap().clone().unwrap();
+        sleep(Duration::from_millis(50));
+        let schema: serde_json::Value =
+            serde_json::from_str(sent.sent().lines().next().unwrap()).unwrap();
+        let device = &schema["params"]["data"]["device"][0];
+        assert_eq!("ACME", device["manufacturer"]);
+        assert_eq!("Thermostat", device["product"]);
+        assert_eq!("A-1", device["serial"]);
+        assert_eq!("1", device["included"]);
+        assert!(device.get("description").is_none());
+    }
+
+    #[test]
+    fn should_update_metadata_of_existing_device() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let id = network
+            .create_device("test_device")
             .inner
-            .borrow()
-            .send
-            .lock()
+            .read()
+            .unwrap()
+            .id;
+        let device = network
+            .device_builder("test_device")
+            .version("2.0")
+            .create();
+        let device = device.inner.read().unwrap();
+        assert_eq!(id, device.id);
+        assert_eq!(Some("2.0"), device.info.version.as_deref());
+    }
+
+    #[test]
+    fn should_remove_device_deleted_by_server() {
+        let deletions = Arc::new(Mutex::new(vec![]));
+        let deletions_sent = Arc::clone(&deletions);
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.on_delete(Box::new(move |d| deletions_sent.lock().unwrap().push(d)));
+        let device_id = network
+            .create_device("test_device")
+            .inner
+            .read()
+            .unwrap()
+            .id;
+        network.create_device("other_device");
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        stream.receive(&delete_rpc(&format!("/device/{}", device_id)));
+        sleep(Duration::from_millis(50));
+        network.stop().unwrap();
+
+        assert_eq!(
+            vec![Deleted::Device {
+                id: device_id,
+                name: String::from("test_device")
+            }],
+            *deletions.lock().unwrap()
+        );
+        assert!(network.device_named("test_device").is_none());
+        let saved = network
+            .store()
+            .load_schema(Uuid::parse_str(DEFAULT_ID).unwrap())
             .unwrap()
-            .as_ref()
             .unwrap();
+        assert_eq!(1, saved.device.len());
+        assert_eq!("other_device", saved.device[0].name);
+    }
+
+    #[test]
+    fn should_recreate_value_deleted_by_server_with_new_id() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test_device");
+        let value_id = ValueSchema::from(&device.create_value("test_value", ValuePermission::R))
+            .meta
+            .id;
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        stream.receive(&delete_rpc(&format!("/value/{}", value_id)));
         sleep(Duration::from_millis(50));
-        assert!(*callback_was_called.lock().unwrap())
+
+        let recreated = device.create_value("test_value", ValuePermission::R);
+        assert_ne!(value_id, ValueSchema::from(&recreated).meta.id);
     }
-    pub fn control_state_rpc(data: &str, id: Uuid) -> String {
-        serde_json::to_string(
-            &RpcRequest::builder()
-                .method(RpcMethod::Put)
-                .data(RpcData::Data(RpcStateData::new(
-                    data,
-                    Utc::now(),
-                    Meta::new_with_uuid(id, MetaType::State),
-                )))
-                .create(),
-        )
-        .unwrap()
+
+    #[test]
+    fn should_forget_handlers_of_entities_deleted_by_server() {
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_sent = Arc::clone(&errors);
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.on_error(Box::new(move |e| errors_sent.lock().unwrap().push(e)));
+        let device = network.create_device("test_device");
+        let device_id = device.inner.read().unwrap().id;
+        let device_control_id = device
+            .create_value("test_value", ValuePermission::RW(Box::new(|_| {})))
+            .control_id();
+        let kept = network.create_device("kept_device");
+        let value = kept.create_value("deleted_value", ValuePermission::RW(Box::new(|_| {})));
+        let value_id = ValueSchema::from(&value).meta.id;
+        let value_control_id = value.control_id();
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        stream.receive(&delete_rpc(&format!("/device/{}", device_id)));
+        stream.receive(&delete_rpc(&format!("/value/{}", value_id)));
+        sleep(Duration::from_millis(50));
+
+        network.create_device("other_device");
+        kept.create_value("other_value", ValuePermission::R);
+        stream.receive(&control_state_rpc("1", device_control_id));
+        stream.receive(&control_state_rpc("1", value_control_id));
+        sleep(Duration::from_millis(50));
+
+        let errors = errors.lock().unwrap();
+        assert!(matches!(
+            errors[0],
+            CommunicationError::UnknownState(id) if id == device_control_id
+        ));
+        assert!(matches!(
+            errors[1],
+            CommunicationError::UnknownState(id) if id == value_control_id
+        ));
     }
-}
 
-pub mod device {
+    #[test]
+    fn should_delete_device_locally_and_in_wappsto() {
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_sent = Arc::clone(&errors);
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.on_error(Box::new(move |e| errors_sent.lock().unwrap().push(e)));
+        let device = network.create_device("test_device");
+        let device_id = device.inner.read().unwrap().id;
+        let control_id = device
+            .create_value("test_value", ValuePermission::RW(Box::new(|_| {})))
+            .control_id();
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        network.delete_device("test_device").unwrap();
+        stream.receive(&control_state_rpc("1", control_id));
+        sleep(Duration::from_millis(50));
 
-    use std::sync::{Arc, Mutex};
+        assert!(stream
+            .sent()
+            .contains(&format!(r#""params":{{"url":"/device/{}"}}"#, device_id)));
+        assert!(network.device_named("test_device").is_none());
+        assert!(network
+            .store()
+            .load_schema(Uuid::parse_str(DEFAULT_ID).unwrap())
+            .unwrap()
+            .unwrap()
+            .device
+            .is_empty());
+        assert!(matches!(
+            errors.lock().unwrap()[0],
+            CommunicationError::UnknownState(id) if id == control_id
+        ));
+        assert!(network.delete_device("test_device").is_err());
+    }
 
-    use crate::{
-        network::{Device, ValuePermission},
-        network_test::connection::WrappedSendMock,
-    };
+    #[test]
+    fn should_delete_value_locally_and_in_wappsto() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test_device");
+        let value_id = ValueSchema::from(&device.create_value("test_value", ValuePermission::R))
+            .meta
+            .id;
+        device.create_value("other_value", ValuePermission::R);
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        device.delete_value("test_value").unwrap();
+        sleep(Duration::from_millis(50));
+
+        assert!(stream
+            .sent()
+            .contains(&format!(r#""params":{{"url":"/value/{}"}}"#, value_id)));
+        assert!(device.value_named("test_value").is_none());
+        let saved = network
+            .store()
+            .load_schema(Uuid::parse_str(DEFAULT_ID).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(1, saved.device[0].value.len());
+        assert_eq!("other_value", saved.device[0].value[0].name);
+        assert!(device.delete_value("test_value").is_err());
+    }
 
     #[test]
-    fn should_create_new_value() {
-        let device: Device<WrappedSendMock> = Device::default();
-        device.create_value("test", ValuePermission::R);
-        assert!(device.value_named("test").is_some())
+    fn should_reject_initial_data_not_matching_value_type() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test_device");
+
+        let created = device
+            .value_builder("temperature")
+            .permission(ValuePermission::R)
+            .value_type(ValueType::Number(NumberSchema::new(0f64, 30f64, 0.5, "°C")))
+            .initial_data("abc")
+            .create();
+
+        assert!(matches!(created, Err(ValidationError::NotANumber(_))));
+        assert!(device.value_named("temperature").is_none());
     }
 
     #[test]
-    fn should_register_callback_on_writable_values() {
-        let callback_was_called = Arc::new(Mutex::new(false));
-        let callback_was_called_sent = Arc::clone(&callback_was_called);
-        let device: Device<WrappedSendMock> = Device::default();
-        let callback = move |_: String| {
-            *callback_was_called_sent.lock().unwrap() = true;
-        };
-        let value = device.create_value("test_value", ValuePermission::RW(Box::new(callback)));
-        value.inner.lock().unwrap().control(String::new());
+    fn should_publish_value_metadata() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test_device");
+        device
+            .value_builder("temperature")
+            .permission(ValuePermission::R)
+            .type_name("temperature")
+            .description("Outdoor temperature")
+            .period("60")
+            .delta("0.5")
+            .initial_data("1")
+            .create()
+            .unwrap();
+        network.start().unwrap();
+        let sent = network.connection().stream.lock().unwrap().clone().unwrap();
+        sleep(Duration::from_millis(50));
+        let schema: serde_json::Value =
+            serde_json::from_str(sent.sent().lines().next().unwrap()).unwrap();
+        let value = &schema["params"]["data"]["device"][0]["value"][0];
+        assert_eq!("temperature", value["type"]);
+        assert_eq!("Outdoor temperature", value["description"]);
+        assert_eq!("60", value["period"]);
+        assert_eq!("0.5", value["delta"]);
+        assert_eq!("1", value["state"][0]["data"]);
+    }
 
-        assert!(*callback_was_called.lock().unwrap())
+    #[test]
+    fn should_post_device_created_after_start() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.start().unwrap();
+        let device = network.device_builder("late").product("sensor").create();
+        sleep(Duration::from_millis(50));
+        let request = last_sent(&network);
+        assert_eq!("POST", request["method"]);
+        assert_eq!(
+            format!("/network/{}/device", network.id()),
+            request["params"]["url"]
+        );
+        assert_eq!("late", request["params"]["data"]["name"]);
+        assert_eq!("sensor", request["params"]["data"]["product"]);
+        assert_eq!(
+            device.inner.read().unwrap().id.to_string(),
+            request["params"]["data"]["meta"]["id"]
+        );
     }
-}
 
-pub mod value {
-    use std::{sync::Arc, thread::sleep, time::Duration};
+    #[test]
+    fn should_post_value_created_after_start() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test_device");
+        network.start().unwrap();
+        device.create_value("late", ValuePermission::R);
+        sleep(Duration::from_millis(50));
+        let request = last_sent(&network);
+        assert_eq!("POST", request["method"]);
+        assert_eq!(
+            format!("/device/{}/value", device.inner.read().unwrap().id),
+            request["params"]["url"]
+        );
+        assert_eq!("late", request["params"]["data"]["name"]);
+        assert_eq!(
+            1,
+            request["params"]["data"]["state"].as_array().unwrap().len()
+        );
+    }
 
-    use crate::{
-        network::{Network, ValuePermission},
-        network_test::network::control_state_rpc,
-    };
+    #[test]
+    fn should_put_only_changed_device_fields() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network
+            .device_builder("test_device")
+            .product("sensor")
+            .version("1.0")
+            .create();
+        network.start().unwrap();
+        network
+            .device_builder("test_device")
+            .product("sensor")
+            .version("1.1")
+            .create();
+        sleep(Duration::from_millis(50));
+        let request = last_sent(&network);
+        assert_eq!("PUT", request["method"]);
+        assert_eq!(
+            format!("/device/{}", device.inner.read().unwrap().id),
+            request["params"]["url"]
+        );
+        let data = request["params"]["data"].as_object().unwrap();
+        assert_eq!(2, data.len());
+        assert_eq!("1.1", data["version"]);
+        assert!(data.contains_key("meta"));
+    }
 
-    use super::{
-        connection::{ConnectionMock, WrappedSendMock},
-        store::StoreMock,
-    };
+    #[test]
+    fn should_put_only_changed_value_fields() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test_device");
+        device
+            .value_builder("temperature")
+            .permission(ValuePermission::R)
+            .period("60")
+            .create()
+            .unwrap();
+        network.start().unwrap();
+        device
+            .value_builder("temperature")
+            .permission(ValuePermission::R)
+            .period("30")
+            .create()
+            .unwrap();
+        sleep(Duration::from_millis(50));
+        let request = last_sent(&network);
+        let data = request["params"]["data"].as_object().unwrap();
+        assert_eq!("PUT", request["method"]);
+        assert_eq!(
+            format!("/value/{}", data["meta"]["id"].as_str().unwrap()),
+            request["params"]["url"]
+        );
+        assert_eq!(2, data.len());
+        assert_eq!("30", data["period"]);
+    }
 
     #[test]
-    fn should_report_state_change_to_server() {
+    fn should_not_send_unchanged_device() {
         let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
             Network::new("test").unwrap();
-        let device = network.create_device("test device");
-        let value = device.create_value("test value", ValuePermission::R);
+        network.create_device("test_device");
         network.start().unwrap();
-        value.report("test report");
-        assert!(network
-            .inner
-            .borrow()
-            .send
+        sleep(Duration::from_millis(50));
+        let before = sent_count(&network);
+        network.create_device("test_device");
+        sleep(Duration::from_millis(50));
+        assert_eq!(before, sent_count(&network));
+    }
+
+    #[test]
+    fn should_not_send_devices_created_before_start() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test_device");
+        device.create_value("test_value", ValuePermission::R);
+        sleep(Duration::from_millis(50));
+        assert_eq!(0, sent_count(&network));
+    }
+
+    fn sent_messages(
+        network: &Network<ConnectionMock, StoreMock, WrappedSendMock>,
+    ) -> Vec<serde_json::Value> {
+        let sent = network
+            .connection()
+            .stream
             .lock()
             .unwrap()
-            .as_ref()
+            .clone()
             .unwrap()
-            .sent_to_server("test report"))
+            .sent();
+        serde_json::Deserializer::from_str(&sent)
+            .into_iter::<serde_json::Value>()
+            .map(|m| m.unwrap())
+            .collect()
+    }
+
+    fn sent_count(network: &Network<ConnectionMock, StoreMock, WrappedSendMock>) -> usize {
+        sent_messages(network).len()
+    }
+
+    fn last_sent(
+        network: &Network<ConnectionMock, StoreMock, WrappedSendMock>,
+    ) -> serde_json::Value {
+        sent_messages(network).pop().unwrap()
     }
 
     #[test]
-    fn should_reference_value_in_callback() {
+    fn should_pass_callbacks_to_reader() {
+        let callback_was_called = Arc::new(Mutex::new(false));
+        let callback_was_called_sent = Arc::clone(&callback_was_called);
+        let callback = move |_: String| {
+            *callback_was_called_sent.lock().unwrap() = true;
+        };
         let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
             Network::new("test").unwrap();
-        let device = network.create_device("test device");
-        let value = device.create_value("test value", ValuePermission::RW(Box::new(|_| {})));
-        let value_arc = Arc::clone(&value.inner);
-        value.on_control(Box::new(move |data: String| {
-            value_arc.lock().unwrap().report(&data)
-        }));
-        let state_id = value.control_id();
+        let device = network.create_device("test_device");
+        let state_id = device
+            .create_value("test_value", ValuePermission::RW(Box::new(callback)))
+            .control_id();
+
         network
             .connection()
             .stream
-            .borrow_mut()
+            .lock()
+            .unwrap()
             .as_mut()
             .unwrap()
-            .receive(&control_state_rpc("test report", state_id));
+            .receive(&control_state_rpc("1", state_id));
         network.start().unwrap();
-        sleep(Duration::from_millis(50));
-
-        assert!(network
+        network
             .inner
-            .borrow()
+            .read()
+            .unwrap()
             .send
             .lock()
             .unwrap()
             .as_ref()
-            .unwrap()
+            .unwrap();
+        sleep(Duration::from_millis(50));
+        assert!(*callback_was_called.lock().unwrap())
+    }
+    #[test]
+    fn should_control_value_created_after_start() {
+        let controlled = Arc::new(Mutex::new(None));
+        let controlled_sent = Arc::clone(&controlled);
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test_device");
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        let state_id = device
+            .create_value(
+                "late",
+                ValuePermission::RW(Box::new(move |data| {
+                    *controlled_sent.lock().unwrap() = Some(data)
+                })),
+            )
+            .control_id();
+        stream.receive(&control_state_rpc("1", state_id));
+        sleep(Duration::from_millis(50));
+        assert_eq!(Some(String::from("1")), *controlled.lock().unwrap());
+    }
+
+    #[test]
+    fn should_control_device_created_after_start() {
+        let controlled = Arc::new(Mutex::new(false));
+        let controlled_sent = Arc::clone(&controlled);
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        let value = network
+            .create_device("hot_plugged")
+            .create_value("switch", ValuePermission::W(Box::new(|_| {})));
+        value.on_control(move |_: bool| *controlled_sent.lock().unwrap() = true);
+        stream.receive(&control_state_rpc("1", value.control_id()));
+        sleep(Duration::from_millis(50));
+        assert!(*controlled.lock().unwrap());
+    }
+
+    #[test]
+    fn should_notify_deletion_of_device_created_after_start() {
+        let deletions = Arc::new(Mutex::new(vec![]));
+        let deletions_sent = Arc::clone(&deletions);
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.on_delete(Box::new(move |d| deletions_sent.lock().unwrap().push(d)));
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        let device_id = network
+            .create_device("hot_plugged")
+            .inner
+            .read()
+            .unwrap()
+            .id;
+        stream.receive(&delete_rpc(&format!("/device/{}", device_id)));
+        sleep(Duration::from_millis(50));
+        assert_eq!(
+            vec![Deleted::Device {
+                id: device_id,
+                name: String::from("hot_plugged")
+            }],
+            *deletions.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn should_not_control_state_removed_after_start() {
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_sent = Arc::clone(&errors);
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.on_error(Box::new(move |e| errors_sent.lock().unwrap().push(e)));
+        let device = network.create_device("test_device");
+        let state_id = device
+            .create_value("test_value", ValuePermission::RW(Box::new(|_| {})))
+            .control_id();
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        device
+            .create_value("test_value", ValuePermission::R)
+            .on_control(|_: f64| {});
+        stream.receive(&control_state_rpc("1", state_id));
+        sleep(Duration::from_millis(50));
+        assert!(matches!(
+            errors.lock().unwrap()[..],
+            [CommunicationError::UnknownState(id)] if id == state_id
+        ));
+    }
+
+    #[test]
+    fn should_acknowledge_report_received_by_wappsto() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let value = network
+            .create_device("test_device")
+            .create_value("test_value", ValuePermission::R);
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        let ack = value.report("1").unwrap();
+        stream.receive(&format!(
+            r#"{{"jsonrpc":"2.0","id":"{}","result":{{"value":true}}}}"#,
+            ack.id()
+        ));
+        assert!(ack.wait().is_ok());
+    }
+
+    #[test]
+    fn should_time_out_report_never_acknowledged() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.set_request_timeout(Duration::from_millis(20));
+        let value = network
+            .create_device("test_device")
+            .create_value("test_value", ValuePermission::R);
+        network.start().unwrap();
+        let ack = value.report("1").unwrap();
+        assert!(matches!(ack.wait(), Err(AckError::TimedOut)));
+    }
+
+    #[test]
+    fn should_be_shareable_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Network>();
+        assert_send_sync::<Device<SendChannel>>();
+        assert_send_sync::<Value<SendChannel>>();
+    }
+
+    #[test]
+    fn should_create_devices_and_report_from_other_threads() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        let workers = (0..4)
+            .map(|i| {
+                let network = network.clone();
+                thread::spawn(move || {
+                    network
+                        .create_device(&format!("device_{}", i))
+                        .create_value("value", ValuePermission::R)
+                        .report("1")
+                        .unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        workers.into_iter().for_each(|w| w.join().unwrap());
+        sleep(Duration::from_millis(50));
+
+        (0..4).for_each(|i| assert!(network.device_named(&format!("device_{}", i)).is_some()));
+        assert_eq!(4, stream.sent().matches("\"/state\"").count());
+    }
+
+    #[test]
+    fn should_pass_communication_errors_to_handler() {
+        let error_was_handled = Arc::new(Mutex::new(false));
+        let error_was_handled_sent = Arc::clone(&error_was_handled);
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.on_error(Box::new(move |_| {
+            *error_was_handled_sent.lock().unwrap() = true
+        }));
+        network
+            .connection()
+            .stream
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .receive(&control_state_rpc("1", Uuid::new_v4()));
+        network.start().unwrap();
+        sleep(Duration::from_millis(50));
+        assert!(*error_was_handled.lock().unwrap())
+    }
+
+    #[test]
+    fn should_autosave_devices_and_values_created_without_stopping() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.set_autosave(Some(fast_autosave()));
+        network.start().unwrap();
+
+        network
+            .create_device("test_device")
+            .create_value("test_value", ValuePermission::R);
+        sleep(Duration::from_millis(200));
+
+        let schema = network.store().load_schema(network.id()).unwrap().unwrap();
+        assert_eq!("test_value", schema.device[0].value[0].name);
+    }
+
+    #[test]
+    fn should_autosave_device_deleted_in_wappsto() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device_id = network
+            .create_device("test_device")
+            .inner
+            .read()
+            .unwrap()
+            .id;
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        network.set_autosave(Some(fast_autosave()));
+
+        stream.receive(&delete_rpc(&format!("/device/{}", device_id)));
+        sleep(Duration::from_millis(200));
+
+        let schema = network.store().load_schema(network.id()).unwrap().unwrap();
+        assert!(schema.device.is_empty());
+    }
+
+    #[test]
+    fn should_autosave_reports_only_when_configured() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let value = network
+            .create_device("test_device")
+            .create_value("test_value", ValuePermission::R);
+        network.set_autosave(Some(fast_autosave()));
+
+        let saved_report = || {
+            network
+                .store()
+                .load_schema(network.id())
+                .unwrap()
+                .map(|schema| schema.device[0].value[0].state[0].data.clone())
+        };
+        sleep(Duration::from_millis(100));
+
+        value.report("1").unwrap();
+        sleep(Duration::from_millis(200));
+        assert_ne!(Some(String::from("1")), saved_report());
+
+        network.set_autosave(Some(Autosave {
+            save_reports: true,
+            ..fast_autosave()
+        }));
+        value.report("0.5").unwrap();
+        sleep(Duration::from_millis(200));
+        assert_eq!(Some(String::from("0.5")), saved_report());
+    }
+
+    #[test]
+    fn should_not_autosave_by_default() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+
+        network.create_device("test_device");
+        sleep(Duration::from_millis(50));
+
+        assert!(network.store().load_schema(network.id()).unwrap().is_none());
+    }
+
+    #[test]
+    fn should_not_autosave_when_turned_off() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.set_autosave(Some(fast_autosave()));
+        network.set_autosave(None);
+
+        network.create_device("test_device");
+        sleep(Duration::from_millis(50));
+
+        assert!(network.store().load_schema(network.id()).unwrap().is_none());
+    }
+
+    fn fast_autosave() -> Autosave {
+        Autosave {
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            ..Autosave::default()
+        }
+    }
+
+    fn delete_rpc(url: &str) -> String {
+        format!(
+            r#"{{"jsonrpc":"2.0","id":"delete","method":"DELETE","params":{{"url":"{}"}}}}"#,
+            url
+        )
+    }
+}
+
+pub mod device {
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        network::{Device, ValuePermission},
+        network_test::connection::WrappedSendMock,
+        schema::{DeviceSchema, StateType, StringSchema, ValueSchema, ValueType},
+    };
+
+    #[test]
+    fn should_create_new_value() {
+        let device: Device<WrappedSendMock> = Device::default();
+        device.create_value("test", ValuePermission::R);
+        assert!(device.value_named("test").is_some())
+    }
+
+    #[test]
+    fn should_register_callback_on_writable_values() {
+        let callback_was_called = Arc::new(Mutex::new(false));
+        let callback_was_called_sent = Arc::clone(&callback_was_called);
+        let device: Device<WrappedSendMock> = Device::default();
+        let callback = move |_: String| {
+            *callback_was_called_sent.lock().unwrap() = true;
+        };
+        let value = device.create_value("test_value", ValuePermission::RW(Box::new(callback)));
+        value
+            .inner
+            .lock()
+            .unwrap()
+            .control(String::from("1"))
+            .unwrap();
+
+        assert!(*callback_was_called.lock().unwrap())
+    }
+
+    #[test]
+    fn should_update_permission_of_persisted_value() {
+        let device: Device<WrappedSendMock> = Device::default();
+        device.create_value("test_value", ValuePermission::R);
+        let device: Device<WrappedSendMock> = Device::from(DeviceSchema::from(device));
+        let persisted = ValueSchema::from(&device.value_named("test_value").unwrap());
+
+        let callback_was_called = Arc::new(Mutex::new(false));
+        let callback_was_called_sent = Arc::clone(&callback_was_called);
+        let value = device
+            .value_builder("test_value")
+            .permission(ValuePermission::RW(Box::new(move |_| {
+                *callback_was_called_sent.lock().unwrap() = true
+            })))
+            .create()
+            .unwrap();
+        value
+            .inner
+            .lock()
+            .unwrap()
+            .control(String::from("1"))
+            .unwrap();
+        let updated = ValueSchema::from(&value);
+
+        assert!(*callback_was_called.lock().unwrap());
+        assert_eq!(persisted.meta.id, updated.meta.id);
+        assert_eq!(persisted.state[0].meta.id, updated.state[0].meta.id);
+        assert_eq!(StateType::Control, updated.state[1].state_type);
+    }
+
+    #[test]
+    fn should_keep_persisted_type_and_metadata_when_not_given() {
+        let device: Device<WrappedSendMock> = Device::default();
+        device
+            .value_builder("test_value")
+            .permission(ValuePermission::R)
+            .value_type(ValueType::String(StringSchema::default()))
+            .type_name("name")
+            .period("60")
+            .create()
+            .unwrap();
+        let device: Device<WrappedSendMock> = Device::from(DeviceSchema::from(device));
+
+        device.create_value("test_value", ValuePermission::RW(Box::new(|_| {})));
+        let value = device
+            .value_builder("test_value")
+            .permission(ValuePermission::RW(Box::new(|_| {})))
+            .delta("1")
+            .initial_data("not a number")
+            .create()
+            .unwrap();
+
+        let updated = ValueSchema::from(&value);
+        assert!(matches!(updated.value_type, ValueType::String(_)));
+        assert_eq!(Some(String::from("name")), updated.type_name);
+        assert_eq!(Some(String::from("60")), updated.period);
+        assert_eq!(Some(String::from("1")), updated.delta);
+    }
+
+    #[test]
+    fn should_keep_state_ids_when_loaded() {
+        let device: Device<WrappedSendMock> = Device::default();
+        device.create_value("test_value", ValuePermission::RW(Box::new(|_| {})));
+        let saved = DeviceSchema::from(device);
+        let loaded = DeviceSchema::from(Device::<WrappedSendMock>::from(saved.clone()));
+        let state_ids = |schema: &DeviceSchema| {
+            schema.value[0]
+                .state
+                .iter()
+                .map(|s| s.meta.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(state_ids(&saved), state_ids(&loaded));
+    }
+}
+
+pub mod value {
+    use std::{
+        sync::{mpsc, Arc, Mutex},
+        thread::{self, sleep},
+        time::Duration,
+    };
+
+    use chrono::{TimeZone, Utc};
+
+    use crate::{
+        fs_store::Store,
+        network::{Network, StateData, ValuePermission},
+        schema::{NumberSchema, StringSchema, ValidationError, ValueType},
+        test_support::control_state_rpc,
+    };
+
+    use super::{
+        connection::{ConnectionMock, WrappedSendMock},
+        store::StoreMock,
+    };
+
+    #[test]
+    fn should_report_state_change_to_server() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test device");
+        let value = device
+            .value_builder("test value")
+            .permission(ValuePermission::R)
+            .value_type(ValueType::String(StringSchema::default()))
+            .create()
+            .unwrap();
+        network.start().unwrap();
+        value.report("test report").unwrap();
+        assert!(network
+            .inner
+            .read()
+            .unwrap()
+            .send
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .sent_to_server("test report"))
+    }
+
+    #[test]
+    fn should_reject_reports_not_matching_value_type() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test device");
+        let value = device.create_value("test value", ValuePermission::R);
+        network.start().unwrap();
+        assert!(value.report("not a number").is_err());
+        assert!(!network
+            .inner
+            .read()
+            .unwrap()
+            .send
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .sent_to_server("not a number"))
+    }
+
+    #[test]
+    fn should_report_typed_data() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test device");
+        let value = device
+            .value_builder("temperature")
+            .permission(ValuePermission::R)
+            .value_type(ValueType::Number(NumberSchema::new(0f64, 30f64, 0.5, "°C")))
+            .create()
+            .unwrap();
+        network.start().unwrap();
+        value.report_typed(21.4).unwrap();
+        assert!(value.report_typed(f64::NAN).is_err());
+        assert!(value.report_typed(31).is_err());
+        let send = network.inner.read().unwrap().send.clone();
+        let send = send.lock().unwrap();
+        assert!(send.as_ref().unwrap().sent_to_server("21.5"));
+        assert!(!send.as_ref().unwrap().sent_to_server("NaN"));
+    }
+
+    #[test]
+    fn should_report_with_explicit_timestamp() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test device");
+        let value = device.create_value("test value", ValuePermission::R);
+        network.start().unwrap();
+        value
+            .report_at("1", Utc.with_ymd_and_hms(2020, 1, 1, 12, 0, 0).unwrap())
+            .unwrap();
+        let send = network.inner.read().unwrap().send.clone();
+        let send = send.lock().unwrap();
+        assert!(send
+            .as_ref()
+            .unwrap()
+            .sent_to_server("2020-01-01T12:00:00Z"));
+    }
+
+    #[test]
+    fn should_upload_history_in_timestamp_order() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test device");
+        let value = device
+            .value_builder("temperature")
+            .permission(ValuePermission::R)
+            .value_type(ValueType::Number(NumberSchema::new(0f64, 30f64, 0.5, "°C")))
+            .create()
+            .unwrap();
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        let acks = value
+            .report_history(vec![
+                (Utc.with_ymd_and_hms(2020, 1, 1, 14, 0, 0).unwrap(), 22.0),
+                (Utc.with_ymd_and_hms(2020, 1, 1, 12, 0, 0).unwrap(), 20.0),
+                (Utc.with_ymd_and_hms(2020, 1, 1, 13, 0, 0).unwrap(), 21.0),
+            ])
+            .unwrap();
+        sleep(Duration::from_millis(50));
+
+        let sent = stream.sent();
+        let batch = serde_json::Deserializer::from_str(&sent)
+            .into_iter::<serde_json::Value>()
+            .last()
+            .unwrap()
+            .unwrap();
+        let data = batch
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["params"]["data"]["data"].as_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(vec!["20", "21", "22"], data);
+        assert!(sent.contains(&format!(r#""id":"{}""#, acks[0].id())));
+    }
+
+    #[test]
+    fn should_acknowledge_each_sample_of_history() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let value = network
+            .create_device("test device")
+            .value_builder("temperature")
+            .permission(ValuePermission::R)
+            .value_type(ValueType::Number(NumberSchema::new(0f64, 30f64, 0.5, "°C")))
+            .create()
+            .unwrap();
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        let acks = value
+            .report_history(vec![
+                (Utc.with_ymd_and_hms(2020, 1, 1, 12, 0, 0).unwrap(), 20.0),
+                (Utc.with_ymd_and_hms(2020, 1, 1, 13, 0, 0).unwrap(), 21.0),
+            ])
+            .unwrap();
+
+        let responses = acks
+            .iter()
+            .map(|ack| {
+                format!(
+                    r#"{{"jsonrpc":"2.0","id":"{}","result":{{"value":true}}}}"#,
+                    ack.id()
+                )
+            })
+            .collect::<Vec<String>>();
+        stream.receive(&format!("[{}]", responses.join(",")));
+
+        assert_eq!(2, acks.len());
+        for ack in acks {
+            assert!(ack.wait().is_ok());
+        }
+    }
+
+    #[test]
+    fn should_not_upload_history_with_invalid_samples() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test device");
+        let value = device.create_value("test value", ValuePermission::R);
+        network.start().unwrap();
+        let result = value.report_history(vec![
+            (Utc.with_ymd_and_hms(2020, 1, 1, 12, 0, 0).unwrap(), "1"),
+            (Utc.with_ymd_and_hms(2020, 1, 1, 13, 0, 0).unwrap(), "2"),
+        ]);
+        assert!(result.is_err());
+        let send = network.inner.read().unwrap().send.clone();
+        let send = send.lock().unwrap();
+        assert!(!send
+            .as_ref()
+            .unwrap()
+            .sent_to_server("2020-01-01T12:00:00Z"));
+    }
+
+    #[test]
+    fn should_report_fresh_reading_on_refresh() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test device");
+        let value = device
+            .value_builder("temperature")
+            .permission(ValuePermission::R)
+            .value_type(ValueType::Number(NumberSchema::new(0f64, 30f64, 0.5, "°C")))
+            .create()
+            .unwrap();
+        value.on_refresh(|| 21.5);
+        let report_id = value.inner.lock().unwrap().report.as_ref().unwrap().id;
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        stream.receive(&format!(
+            r#"{{"jsonrpc":"2.0","id":"refresh","method":"GET","params":{{"url":"/state/{}"}}}}"#,
+            report_id
+        ));
+        network.start().unwrap();
+        sleep(Duration::from_millis(50));
+
+        let send = network.inner.read().unwrap().send.clone();
+        let send = send.lock().unwrap();
+        assert!(send.as_ref().unwrap().sent_to_server("21.5"));
+    }
+
+    #[test]
+    fn should_report_last_report_again_on_refresh_without_handler() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let value = network
+            .create_device("test device")
+            .value_builder("temperature")
+            .permission(ValuePermission::R)
+            .value_type(ValueType::Number(NumberSchema::new(0f64, 30f64, 0.5, "°C")))
+            .create()
+            .unwrap();
+        value.report("21.5").unwrap();
+        let report_id = value.inner.lock().unwrap().report.as_ref().unwrap().id;
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+        sleep(Duration::from_millis(50));
+        let reported = stream.sent().matches("\"21.5\"").count();
+
+        stream.receive(&format!(
+            r#"{{"jsonrpc":"2.0","id":"refresh","method":"GET","params":{{"url":"/state/{}"}}}}"#,
+            report_id
+        ));
+        sleep(Duration::from_millis(50));
+
+        assert_eq!(reported + 1, stream.sent().matches("\"21.5\"").count());
+        assert!(stream.sent().contains(r#""id":"refresh","result""#));
+    }
+
+    #[test]
+    fn should_reject_refresh_without_handler_or_report() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let value = network
+            .create_device("test device")
+            .value_builder("temperature")
+            .permission(ValuePermission::R)
+            .create()
+            .unwrap();
+        let report_id = value.inner.lock().unwrap().report.as_ref().unwrap().id;
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+
+        stream.receive(&format!(
+            r#"{{"jsonrpc":"2.0","id":"refresh","method":"GET","params":{{"url":"/state/{}"}}}}"#,
+            report_id
+        ));
+        sleep(Duration::from_millis(50));
+
+        assert!(stream.sent().contains("-32602"));
+        assert!(!stream.sent().contains(r#""id":"refresh","result""#));
+    }
+
+    #[test]
+    fn should_replace_refresh_handler_while_refreshing() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let value = network
+            .create_device("test device")
+            .value_builder("temperature")
+            .permission(ValuePermission::R)
+            .value_type(ValueType::Number(NumberSchema::new(0f64, 30f64, 0.5, "°C")))
+            .create()
+            .unwrap();
+        let (started, refreshing) = mpsc::channel();
+        value.on_refresh(move || {
+            started.send(()).unwrap();
+            sleep(Duration::from_millis(50));
+            21.5
+        });
+        let report_id = value.inner.lock().unwrap().report.as_ref().unwrap().id;
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+
+        stream.receive(&format!(
+            r#"{{"jsonrpc":"2.0","id":"refresh","method":"GET","params":{{"url":"/state/{}"}}}}"#,
+            report_id
+        ));
+        refreshing.recv_timeout(Duration::from_secs(1)).unwrap();
+        let (replaced, was_replaced) = mpsc::channel();
+        let replacing = value.clone();
+        thread::spawn(move || {
+            replacing.on_refresh(|| 22.0);
+            replaced.send(()).unwrap();
+        });
+
+        was_replaced.recv_timeout(Duration::from_secs(1)).unwrap();
+        sleep(Duration::from_millis(100));
+        let send = network.inner.read().unwrap().send.clone();
+        let send = send.lock().unwrap();
+        assert!(send.as_ref().unwrap().sent_to_server("21.5"));
+    }
+
+    #[test]
+    fn should_replace_control_handler_while_handling_control() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let value = network
+            .create_device("test device")
+            .value_builder("test value")
+            .permission(ValuePermission::RW(Box::new(|_| {})))
+            .value_type(ValueType::String(StringSchema::default()))
+            .create()
+            .unwrap();
+        let (started, controlling) = mpsc::channel();
+        let reporting = value.clone();
+        value.on_control(move |data: String| {
+            started.send(()).unwrap();
+            sleep(Duration::from_millis(50));
+            reporting.report(&data).unwrap();
+        });
+        let state_id = value.control_id();
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        network.start().unwrap();
+
+        stream.receive(&control_state_rpc("controlled", state_id));
+        controlling.recv_timeout(Duration::from_secs(1)).unwrap();
+        let (replaced, was_replaced) = mpsc::channel();
+        let replacing = value.clone();
+        thread::spawn(move || {
+            replacing.on_control(|_: String| {});
+            replaced.send(()).unwrap();
+        });
+
+        was_replaced.recv_timeout(Duration::from_secs(1)).unwrap();
+        sleep(Duration::from_millis(100));
+        let send = network.inner.read().unwrap().send.clone();
+        let send = send.lock().unwrap();
+        assert!(send.as_ref().unwrap().sent_to_server("controlled"));
+    }
+
+    #[test]
+    fn should_fail_to_report_on_write_only_value() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test device");
+        let value = device.create_value("test value", ValuePermission::W(Box::new(|_| {})));
+        assert!(value.report("1").is_err());
+    }
+
+    #[test]
+    fn should_queue_reports_until_started() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test device");
+        let value = device
+            .value_builder("test value")
+            .permission(ValuePermission::R)
+            .value_type(ValueType::String(StringSchema::default()))
+            .create()
+            .unwrap();
+        value.report("offline report").unwrap();
+        network.start().unwrap();
+        let sent = network.connection().stream.lock().unwrap().clone().unwrap();
+        sleep(Duration::from_millis(50));
+        let sent = sent.sent();
+        assert!(sent.contains("offline report"));
+        assert!(sent.find("offline report") > sent.find(&network.id().to_string()));
+    }
+
+    #[test]
+    fn should_reference_value_in_callback() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test device");
+        let value = device
+            .value_builder("test value")
+            .permission(ValuePermission::RW(Box::new(|_| {})))
+            .value_type(ValueType::String(StringSchema::default()))
+            .create()
+            .unwrap();
+        let value_arc = Arc::clone(&value.inner);
+        value.on_control(Box::new(move |data: String| {
+            value_arc.lock().unwrap().report(&data).unwrap();
+        }));
+        let state_id = value.control_id();
+        network
+            .connection()
+            .stream
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .receive(&control_state_rpc("test report", state_id));
+        network.start().unwrap();
+        sleep(Duration::from_millis(50));
+
+        assert!(network
+            .inner
+            .read()
+            .unwrap()
+            .send
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
             .sent_to_server("test report"))
     }
+
+    #[test]
+    fn should_parse_typed_controls() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test device");
+        let value = device
+            .value_builder("setpoint")
+            .permission(ValuePermission::RW(Box::new(|_| {})))
+            .value_type(ValueType::Number(NumberSchema::new(0f64, 30f64, 0.5, "°C")))
+            .create()
+            .unwrap();
+        let received = Arc::new(Mutex::new(vec![]));
+        let received_sent = Arc::clone(&received);
+        value.on_control::<f64>(move |setpoint| received_sent.lock().unwrap().push(setpoint));
+
+        let inner = value.inner.lock().unwrap();
+        inner.control(String::from("21.5")).unwrap();
+        assert_eq!(
+            Err(ValidationError::OutOfRange(31f64, 0f64, 30f64)),
+            inner.control(String::from("31"))
+        );
+        assert!(inner.control(String::from("warm")).is_err());
+        assert_eq!(vec![21.5], *received.lock().unwrap());
+    }
+
+    #[test]
+    fn should_parse_boolean_controls() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let device = network.create_device("test device");
+        let value = device.create_value("switch", ValuePermission::W(Box::new(|_| {})));
+        let received = Arc::new(Mutex::new(vec![]));
+        let received_sent = Arc::clone(&received);
+        value.on_control::<bool>(move |on| received_sent.lock().unwrap().push(on));
+
+        let inner = value.inner.lock().unwrap();
+        inner.control(String::from("1")).unwrap();
+        inner.control(String::from("false")).unwrap();
+        assert!(inner.control(String::from("2")).is_err());
+        assert_eq!(vec![true, false], *received.lock().unwrap());
+    }
+
+    #[test]
+    fn should_reject_out_of_range_controls_from_server() {
+        let callback_was_called = Arc::new(Mutex::new(false));
+        let callback_was_called_sent = Arc::clone(&callback_was_called);
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        network.on_error(Box::new(|_| {}));
+        let device = network.create_device("test device");
+        let value = device
+            .value_builder("setpoint")
+            .permission(ValuePermission::W(Box::new(move |_| {
+                *callback_was_called_sent.lock().unwrap() = true
+            })))
+            .value_type(ValueType::Number(NumberSchema::new(0f64, 30f64, 0.5, "°C")))
+            .create()
+            .unwrap();
+        let stream = network.connection().stream.lock().unwrap().clone().unwrap();
+        stream.receive(&control_state_rpc("31", value.control_id()));
+        network.start().unwrap();
+        sleep(Duration::from_millis(50));
+
+        assert!(stream.sent().contains("-32602"));
+        assert!(!*callback_was_called.lock().unwrap());
+    }
+
+    #[test]
+    fn should_remember_last_report() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let value = network
+            .create_device("test device")
+            .create_value("test value", ValuePermission::R);
+        assert_eq!(None, value.last_report());
+
+        value.report("1").unwrap();
+        value
+            .report_at("0", Utc.with_ymd_and_hms(2020, 1, 1, 12, 0, 0).unwrap())
+            .unwrap();
+        assert!(value.report("not a number").is_err());
+
+        assert_eq!(Some(String::from("1")), value.last_report().map(|l| l.data));
+    }
+
+    #[test]
+    fn should_remember_last_valid_control() {
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let value = network
+            .create_device("test device")
+            .create_value("test value", ValuePermission::RW(Box::new(|_| {})));
+        value.on_control(|_: f64| {});
+
+        value
+            .inner
+            .lock()
+            .unwrap()
+            .control(String::from("0.5"))
+            .unwrap();
+        assert!(value
+            .inner
+            .lock()
+            .unwrap()
+            .control(String::from("2"))
+            .is_err());
+
+        assert_eq!(
+            Some(String::from("0.5")),
+            value.last_control().map(|l| l.data)
+        );
+    }
+
+    #[test]
+    fn should_restore_and_republish_last_state_data_after_restart() {
+        let reported_at = Utc.with_ymd_and_hms(2020, 1, 1, 12, 0, 0).unwrap();
+        let network: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::new("test").unwrap();
+        let value = network
+            .create_device("test device")
+            .create_value("test value", ValuePermission::RW(Box::new(|_| {})));
+        value.report_at("1", reported_at).unwrap();
+        value
+            .inner
+            .lock()
+            .unwrap()
+            .control(String::from("0.5"))
+            .unwrap();
+        network.stop().unwrap();
+        let store = StoreMock::default();
+        store
+            .save_schema(network.store().load_schema(network.id()).unwrap().unwrap())
+            .unwrap();
+
+        let restarted: Network<ConnectionMock, StoreMock, WrappedSendMock> =
+            Network::with_store("test", store).unwrap();
+        let value = restarted
+            .create_device("test device")
+            .create_value("test value", ValuePermission::RW(Box::new(|_| {})));
+        let stream = restarted
+            .connection()
+            .stream
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap();
+        restarted.start().unwrap();
+        sleep(Duration::from_millis(50));
+
+        assert_eq!(Some(StateData::new("1", reported_at)), value.last_report());
+        assert_eq!(
+            Some(String::from("0.5")),
+            value.last_control().map(|l| l.data)
+        );
+        let schema: serde_json::Value =
+            serde_json::from_str(stream.sent().lines().next().unwrap()).unwrap();
+        let states = &schema["params"]["data"]["device"][0]["value"][0]["state"];
+        let report = states
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|s| s["type"] == "Report")
+            .unwrap();
+        assert_eq!("1", report["data"]);
+        assert!(report["timestamp"]
+            .as_str()
+            .unwrap()
+            .starts_with("2020-01-01T12:00:00"));
+    }
 }
 
 pub mod connection {
     use crate::{
         certs::Certs,
-        communication::{self, CallbackMap},
+        communication::{self, CallbackRegistry, ErrorHandler, Pending, SchemaSource, Session},
         connection::{Connect, WappstoServers, WrappedSend},
+        queue::MessageQueue,
         stream_mock::StreamMock,
     };
-    use std::{cell::RefCell, error::Error, sync::mpsc::Sender};
+    use std::{
+        error::Error,
+        sync::{mpsc::Sender, Arc, Mutex},
+    };
 
     pub struct ConnectionMock {
-        pub is_started: RefCell<bool>,
-        pub was_closed: bool,
-        pub stream: RefCell<Option<StreamMock>>,
+        pub is_started: Mutex<bool>,
+        pub was_closed: Mutex<bool>,
+        pub stream: Mutex<Option<StreamMock>>,
+        pub schema: Mutex<Option<SchemaSource>>,
+        session: Mutex<Option<Session>>,
     }
 
     impl Connect<WrappedSendMock> for ConnectionMock {
         fn new(_certs: Certs, _server: WappstoServers) -> Self {
             Self {
-                is_started: RefCell::new(false),
-                was_closed: false,
-                stream: RefCell::new(Some(StreamMock::new())),
+                is_started: Mutex::new(false),
+                was_closed: Mutex::new(false),
+                stream: Mutex::new(Some(StreamMock::new())),
+                schema: Mutex::new(None),
+                session: Mutex::new(None),
             }
         }
 
-        fn start(&self, callbacks: CallbackMap) -> Result<WrappedSendMock, Box<dyn Error>> {
-            *self.is_started.borrow_mut() = true;
-            Ok(WrappedSendMock::new(communication::start(
+        fn start(
+            &self,
+            callbacks: CallbackRegistry,
+            pending: Pending,
+            on_error: ErrorHandler,
+            _queue: Arc<Mutex<MessageQueue>>,
+            schema: SchemaSource,
+        ) -> Result<WrappedSendMock, Box<dyn Error>> {
+            *self.is_started.lock().unwrap() = true;
+            self.schema.lock().unwrap().replace(schema);
+            let session = communication::start(
                 callbacks,
-                self.stream.borrow_mut().take().unwrap(),
-            )))
+                pending,
+                on_error,
+                self.stream.lock().unwrap().clone().unwrap(),
+            );
+            let send = session.sender();
+            self.session.lock().unwrap().replace(session);
+            Ok(WrappedSendMock::new(send))
+        }
+
+        fn stop(&self) -> Result<(), Box<dyn Error>> {
+            *self.was_closed.lock().unwrap() = true;
+            let session = self.session.lock().unwrap().take();
+            match session {
+                Some(session) => session.stop(),
+                None => Ok(()),
+            }
         }
     }
 
     pub struct WrappedSendMock {
-        received: RefCell<String>,
+        received: Mutex<String>,
         send: Sender<String>,
     }
 
     impl WrappedSendMock {
         pub fn new(send: Sender<String>) -> Self {
             Self {
-                received: RefCell::new(String::new()),
+                received: Mutex::new(String::new()),
                 send,
             }
         }
 
         pub fn sent_to_server(&self, term: &str) -> bool {
-            self.received.borrow().contains(term)
+            self.received.lock().unwrap().contains(term)
         }
     }
     impl WrappedSend for WrappedSendMock {
         fn send(&self, msg: String) -> Result<(), Box<dyn Error>> {
-            self.received.borrow_mut().push_str(&msg);
+            self.received.lock().unwrap().push_str(&msg);
             self.send.send(msg).unwrap();
             Ok(())
         }
@@ -329,13 +1739,13 @@ pub mod store {
     use uuid::Uuid;
 
     use crate::{certs::Certs, fs_store::Store, schema::Schema};
-    use std::{cell::RefCell, collections::HashMap, error::Error};
+    use std::{collections::HashMap, error::Error, sync::Mutex};
     pub const DEFAULT_ID: &str = "00000000-0000-0000-0000-000000000000";
 
     use openssl::{pkey::PKey, x509::X509};
 
     pub struct StoreMock {
-        pub schemas: RefCell<HashMap<Uuid, Schema>>,
+        pub schemas: Mutex<HashMap<Uuid, Schema>>,
     }
 
     impl Store for StoreMock {
@@ -349,20 +1759,20 @@ pub mod store {
         }
 
         fn save_schema(&self, schema: Schema) -> Result<(), Box<dyn Error>> {
-            self.schemas.borrow_mut().insert(schema.meta.id, schema);
+            self.schemas.lock().unwrap().insert(schema.meta.id, schema);
 
             Ok(())
         }
 
-        fn load_schema(&self, id: Uuid) -> Option<Schema> {
-            self.schemas.borrow().get(&id).cloned()
+        fn load_schema(&self, id: Uuid) -> Result<Option<Schema>, Box<dyn Error>> {
+            Ok(self.schemas.lock().unwrap().get(&id).cloned())
         }
     }
 
     impl Default for StoreMock {
         fn default() -> Self {
             StoreMock {
-                schemas: RefCell::new(HashMap::new()),
+                schemas: Mutex::new(HashMap::new()),
             }
         }
     }
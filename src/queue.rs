@@ -0,0 +1,237 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fs::{read_to_string, rename, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+use crate::{
+    communication::{CommunicationError, ErrorHandler},
+    rpc::{RpcData, RpcMethod, RpcRequest},
+};
+
+///What to do when a message is queued while the queue is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    ///Discard the oldest queued message to make room
+    DropOldest,
+    ///Discard the message being queued
+    DropNewest,
+    ///Keep only the latest report for each state. If the queue is still full, the oldest message
+    ///is discarded.
+    CoalescePerState,
+}
+
+///Bounded buffer for messages sent while there is no connection to Wappsto. The queue is flushed
+///in order once the network is started or the connection is re-established. If backed by a file,
+///the queue survives restarts of the application. Queued messages are appended to the file, which
+///is replaced as a whole only when messages leave the queue or it has grown to twice the capacity.
+pub struct MessageQueue {
+    capacity: usize,
+    policy: OverflowPolicy,
+    messages: VecDeque<QueuedMessage>,
+    file: Option<PathBuf>,
+    logged: usize,
+    on_error: Option<ErrorHandler>,
+}
+
+struct QueuedMessage {
+    state: Option<Uuid>,
+    msg: String,
+}
+
+impl QueuedMessage {
+    fn new(msg: String) -> Self {
+        Self {
+            state: report_state(&msg),
+            msg,
+        }
+    }
+}
+
+impl MessageQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            capacity,
+            policy,
+            messages: VecDeque::new(),
+            file: None,
+            logged: 0,
+            on_error: None,
+        }
+    }
+
+    ///Create a queue persisted to `path`, loading any messages left there by a previous run.
+    ///Lines that are not valid JSON, e.g. one cut short by a crash, are skipped.
+    pub fn with_file(
+        capacity: usize,
+        policy: OverflowPolicy,
+        path: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut queue = Self::new(capacity, policy);
+        queue.file = Some(PathBuf::from(path));
+        if Path::new(path).exists() {
+            read_to_string(path)?
+                .lines()
+                .filter(|l| serde_json::from_str::<serde_json::Value>(l).is_ok())
+                .for_each(|l| {
+                    queue.enqueue(String::from(l));
+                });
+            queue.rewrite()?;
+        }
+        Ok(queue)
+    }
+
+    pub fn push(&mut self, msg: String) {
+        if self.enqueue(msg) {
+            let persisted = self.append();
+            self.report(persisted);
+        }
+    }
+
+    ///Put messages that could not be delivered back at the front of the queue. They are older
+    ///than everything queued, so the overflow policy is applied as if they had been queued first.
+    pub fn requeue(&mut self, msgs: Vec<String>) {
+        let queued = self
+            .messages
+            .drain(..)
+            .map(|m| m.msg)
+            .collect::<Vec<String>>();
+        msgs.into_iter().chain(queued).for_each(|msg| {
+            self.enqueue(msg);
+        });
+        let persisted = self.rewrite();
+        self.report(persisted);
+    }
+
+    pub fn drain(&mut self) -> Vec<String> {
+        let messages = self.messages.drain(..).map(|m| m.msg).collect();
+        if self.logged > 0 {
+            let persisted = self.rewrite();
+            self.report(persisted);
+        }
+        messages
+    }
+
+    ///Report failures to write the file to `handler` rather than stderr
+    pub(crate) fn set_error_handler(&mut self, handler: ErrorHandler) {
+        self.on_error = Some(handler)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    ///Add a message subject to the overflow policy. Returns whether it was queued.
+    fn enqueue(&mut self, msg: String) -> bool {
+        let message = QueuedMessage::new(msg);
+        if self.policy == OverflowPolicy::CoalescePerState && message.state.is_some() {
+            self.messages.retain(|m| m.state != message.state);
+        }
+        if self.messages.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropNewest => return false,
+                OverflowPolicy::DropOldest | OverflowPolicy::CoalescePerState => {
+                    self.messages.pop_front();
+                }
+            }
+        }
+        if self.capacity == 0 {
+            return false;
+        }
+        self.messages.push_back(message);
+        true
+    }
+
+    ///Append the latest message to the file. Loading the file queues its messages again, so
+    ///those since dropped are dropped again the same way.
+    fn append(&mut self) -> io::Result<()> {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if self.logged >= 2 * self.capacity {
+            return self.rewrite();
+        }
+        if let Some(message) = self.messages.back() {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", message.msg)?;
+            self.logged += 1;
+        }
+        Ok(())
+    }
+
+    ///Replace the file with the current messages. The messages are written to a temporary file
+    ///first, so the file is never left half written.
+    fn rewrite(&mut self) -> io::Result<()> {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let mut file = File::create(&temp)?;
+        for message in &self.messages {
+            writeln!(file, "{}", message.msg)?;
+        }
+        file.sync_all()?;
+        rename(&temp, path)?;
+        self.logged = self.messages.len();
+        Ok(())
+    }
+
+    fn report(&self, persisted: io::Result<()>) {
+        if let Err(e) = persisted {
+            let e = CommunicationError::QueueFailed(e);
+            match &self.on_error {
+                Some(handler) => handler.lock().unwrap()(e),
+                None => eprintln!("{}", e),
+            }
+        }
+    }
+}
+
+impl Default for MessageQueue {
+    fn default() -> Self {
+        Self::new(1000, OverflowPolicy::DropOldest)
+    }
+}
+
+///The state a message reports on, if it is a report
+pub(crate) fn report_state(msg: &str) -> Option<Uuid> {
+    serde_json::from_str::<RpcRequest>(msg)
+        .ok()
+        .as_ref()
+        .and_then(reported_state)
+}
+
+///The reports in a message, each as a message of its own along with the state it reports on. A
+///batch of requests, e.g. of historical samples, holds its reports in timestamp order.
+pub(crate) fn reports(msg: &str) -> Vec<(Uuid, String)> {
+    match serde_json::from_str::<Vec<RpcRequest>>(msg) {
+        Ok(batch) => batch
+            .iter()
+            .filter_map(|request| {
+                let state = reported_state(request)?;
+                Some((state, serde_json::to_string(request).ok()?))
+            })
+            .collect(),
+        Err(_) => report_state(msg)
+            .map(|state| (state, String::from(msg)))
+            .into_iter()
+            .collect(),
+    }
+}
+
+fn reported_state(request: &RpcRequest) -> Option<Uuid> {
+    match (&request.method, &request.params.data) {
+        (RpcMethod::Put, RpcData::Data(d)) => Some(d.meta.id),
+        _ => None,
+    }
+}
@@ -0,0 +1,152 @@
+mod queue {
+    use std::{
+        env,
+        fs::{read_to_string, remove_file, write},
+        sync::{Arc, Mutex},
+    };
+
+    use uuid::Uuid;
+
+    use crate::{
+        communication::CommunicationError,
+        queue::{MessageQueue, OverflowPolicy},
+        test_support::report_rpc,
+    };
+
+    #[test]
+    fn should_drop_oldest_when_full() {
+        let mut queue = MessageQueue::new(2, OverflowPolicy::DropOldest);
+        queue.push(String::from("1"));
+        queue.push(String::from("2"));
+        queue.push(String::from("3"));
+        assert_eq!(vec!["2", "3"], queue.drain());
+    }
+
+    #[test]
+    fn should_drop_newest_when_full() {
+        let mut queue = MessageQueue::new(2, OverflowPolicy::DropNewest);
+        queue.push(String::from("1"));
+        queue.push(String::from("2"));
+        queue.push(String::from("3"));
+        assert_eq!(vec!["1", "2"], queue.drain());
+    }
+
+    #[test]
+    fn should_keep_latest_report_per_state_when_coalescing() {
+        let mut queue = MessageQueue::new(10, OverflowPolicy::CoalescePerState);
+        let first_state = Uuid::new_v4();
+        let second_state = Uuid::new_v4();
+        queue.push(report_rpc("1", first_state));
+        queue.push(report_rpc("2", second_state));
+        queue.push(report_rpc("3", first_state));
+        let queued = queue.drain();
+        assert_eq!(2, queued.len());
+        assert!(queued[0].contains("\"2\""));
+        assert!(queued[1].contains("\"3\""));
+    }
+
+    #[test]
+    fn should_put_requeued_messages_first() {
+        let mut queue = MessageQueue::default();
+        queue.push(String::from("3"));
+        queue.requeue(vec![String::from("1"), String::from("2")]);
+        assert_eq!(vec!["1", "2", "3"], queue.drain());
+    }
+
+    #[test]
+    fn should_apply_overflow_policy_to_requeued_messages() {
+        let mut queue = MessageQueue::new(2, OverflowPolicy::DropOldest);
+        queue.push(String::from("3"));
+        queue.push(String::from("4"));
+        queue.requeue(vec![String::from("1"), String::from("2")]);
+        assert_eq!(vec!["3", "4"], queue.drain());
+
+        let mut queue = MessageQueue::new(2, OverflowPolicy::DropNewest);
+        queue.push(String::from("3"));
+        queue.requeue(vec![String::from("1"), String::from("2")]);
+        assert_eq!(vec!["1", "2"], queue.drain());
+    }
+
+    #[test]
+    fn should_coalesce_requeued_reports() {
+        let mut queue = MessageQueue::new(10, OverflowPolicy::CoalescePerState);
+        let state = Uuid::new_v4();
+        queue.push(report_rpc("2", state));
+        queue.requeue(vec![report_rpc("1", state)]);
+        let queued = queue.drain();
+        assert_eq!(1, queued.len());
+        assert!(queued[0].contains("\"2\""));
+    }
+
+    #[test]
+    fn should_restore_queue_from_file() {
+        let path = env::temp_dir().join(Uuid::new_v4().to_string());
+        let path = path.to_str().unwrap();
+        let mut queue = MessageQueue::with_file(10, OverflowPolicy::DropOldest, path).unwrap();
+        queue.push(report_rpc("1", Uuid::new_v4()));
+        queue.push(report_rpc("2", Uuid::new_v4()));
+
+        let restored = MessageQueue::with_file(10, OverflowPolicy::DropOldest, path)
+            .unwrap()
+            .drain();
+        remove_file(path).unwrap();
+        assert_eq!(queue.drain(), restored);
+    }
+
+    #[test]
+    fn should_skip_lines_cut_short_when_restoring() {
+        let path = env::temp_dir().join(Uuid::new_v4().to_string());
+        let path = path.to_str().unwrap();
+        let report = report_rpc("1", Uuid::new_v4());
+        write(path, format!("{}\n{}", report, &report[..report.len() / 2])).unwrap();
+
+        let restored = MessageQueue::with_file(10, OverflowPolicy::DropOldest, path)
+            .unwrap()
+            .drain();
+        remove_file(path).unwrap();
+
+        assert_eq!(vec![report], restored);
+    }
+
+    #[test]
+    fn should_keep_file_bounded_while_offline() {
+        let path = env::temp_dir().join(Uuid::new_v4().to_string());
+        let path = path.to_str().unwrap();
+        let mut queue = MessageQueue::with_file(5, OverflowPolicy::DropOldest, path).unwrap();
+        for i in 0..100 {
+            queue.push(i.to_string());
+        }
+
+        let lines = read_to_string(path).unwrap().lines().count();
+        let restored = MessageQueue::with_file(5, OverflowPolicy::DropOldest, path)
+            .unwrap()
+            .drain();
+        remove_file(path).unwrap();
+
+        assert!(lines <= 10);
+        assert_eq!(vec!["95", "96", "97", "98", "99"], restored);
+    }
+
+    #[test]
+    fn should_report_failure_to_persist() {
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_sent = Arc::clone(&errors);
+        let path = env::temp_dir()
+            .join(Uuid::new_v4().to_string())
+            .join("queue");
+        let mut queue =
+            MessageQueue::with_file(10, OverflowPolicy::DropOldest, path.to_str().unwrap())
+                .unwrap();
+        queue.set_error_handler(Arc::new(Mutex::new(Box::new(move |e| {
+            errors_sent.lock().unwrap().push(e)
+        }))));
+
+        queue.push(String::from("1"));
+
+        assert!(matches!(
+            errors.lock().unwrap()[..],
+            [CommunicationError::QueueFailed(_)]
+        ));
+        assert_eq!(vec!["1"], queue.drain());
+    }
+}
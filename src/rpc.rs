@@ -1,15 +1,27 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
 use uuid::Uuid;
 
-use crate::schema::{Meta, Schema};
+use serde_json::{Map, Value};
+
+use crate::schema::{DeviceSchema, Meta, Schema, ValueSchema};
 
 pub const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S.%fZ";
 
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
 #[derive(Serialize, Deserialize)]
 pub struct RpcRequest {
     jsonrpc: String,
-    method: RpcMethod,
+    pub method: RpcMethod,
     pub id: String,
     pub params: RpcParams,
 }
@@ -80,33 +92,58 @@ pub enum RpcMethod {
 pub enum RpcType {
     Network,
     State,
+    Device(Uuid),
+    Value(Uuid),
+    ///The devices of the network with the given id
+    NetworkDevices(Uuid),
+    ///The values of the device with the given id
+    DeviceValues(Uuid),
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct RpcParams {
     url: String,
+    ///Left out of requests without data, e.g. to delete something
+    #[serde(default, skip_serializing_if = "RpcData::is_none")]
     pub data: RpcData,
 }
 
 impl RpcParams {
     pub fn new(rpc_type: RpcType, data: RpcData) -> Self {
-        let url = String::from("/")
-            + match rpc_type {
-                RpcType::Network => "network",
-                RpcType::State => "state",
-            };
+        let url = match rpc_type {
+            RpcType::Network => String::from("/network"),
+            RpcType::State => String::from("/state"),
+            RpcType::Device(id) => format!("/device/{}", id),
+            RpcType::Value(id) => format!("/value/{}", id),
+            RpcType::NetworkDevices(id) => format!("/network/{}/device", id),
+            RpcType::DeviceValues(id) => format!("/device/{}/value", id),
+        };
         Self { url, data }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Default, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum RpcData {
     Schema(Schema),
     Data(RpcStateData),
+    #[serde(skip_deserializing)]
+    Device(DeviceSchema),
+    #[serde(skip_deserializing)]
+    Value(ValueSchema),
+    ///Only the fields that changed
+    #[serde(skip_deserializing)]
+    Partial(Map<String, Value>),
+    #[default]
     None,
 }
 
+impl RpcData {
+    pub fn is_none(&self) -> bool {
+        matches!(self, Self::None)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct RpcStateData {
     pub data: String,
@@ -151,3 +188,86 @@ impl RpcResponseResult {
         Self { success }
     }
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct RpcErrorResponse {
+    jsonrpc: String,
+    id: Option<String>,
+    pub error: RpcError,
+}
+
+impl RpcErrorResponse {
+    pub fn new(id: Option<String>, error: RpcError) -> Self {
+        Self {
+            jsonrpc: String::from("2.0"),
+            id,
+            error,
+        }
+    }
+
+    pub fn parse_error() -> Self {
+        Self::new(None, RpcError::new(PARSE_ERROR, "Parse error"))
+    }
+
+    pub fn invalid_request(id: Option<String>) -> Self {
+        Self::new(id, RpcError::new(INVALID_REQUEST, "Invalid request"))
+    }
+
+    pub fn invalid_params(id: String, message: &str) -> Self {
+        Self::new(Some(id), RpcError::new(INVALID_PARAMS, message))
+    }
+}
+
+///An error returned in response to a request, e.g. by Wappsto when it rejects a report or schema
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    ///Further details, such as the fields Wappsto found invalid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: &str) -> Self {
+        Self {
+            code,
+            message: String::from(message),
+            data: None,
+        }
+    }
+
+    pub fn kind(&self) -> RpcErrorKind {
+        match self.code {
+            PARSE_ERROR => RpcErrorKind::ParseError,
+            INVALID_REQUEST => RpcErrorKind::InvalidRequest,
+            METHOD_NOT_FOUND => RpcErrorKind::MethodNotFound,
+            INVALID_PARAMS => RpcErrorKind::InvalidParams,
+            INTERNAL_ERROR => RpcErrorKind::InternalError,
+            code => RpcErrorKind::Server(code),
+        }
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)?;
+        match &self.data {
+            Some(data) => write!(f, ": {}", data),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for RpcError {}
+
+///The JSON-RPC error codes. Anything else is specific to the server, e.g. Wappsto denying access.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RpcErrorKind {
+    ParseError,
+    InvalidRequest,
+    MethodNotFound,
+    InvalidParams,
+    InternalError,
+    Server(i64),
+}
@@ -1,5 +1,11 @@
-use chrono::Utc;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
 use uuid::Uuid;
 
 use crate::rpc::DATE_FORMAT;
@@ -28,6 +34,8 @@ impl Schema {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DeviceSchema {
     pub name: String,
+    #[serde(flatten)]
+    pub info: DeviceInfo,
     pub value: Vec<ValueSchema>,
     pub meta: Meta,
 }
@@ -36,26 +44,68 @@ impl DeviceSchema {
     pub fn new(name: &str, id: Uuid) -> Self {
         DeviceSchema {
             name: name.to_owned(),
+            info: DeviceInfo::default(),
             value: vec![],
             meta: Meta::new_with_uuid(id, MetaType::Device),
         }
     }
 }
 
+///Descriptive metadata of a device. Every field is optional.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DeviceInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    ///The protocol the device is reached by, e.g. `"Zigbee"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    ///How the device communicates, e.g. `"always"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub communication: Option<String>,
+    ///Whether the device is included in the network, `"1"` or `"0"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub included: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ValueSchema {
     pub name: String,
     pub permission: Permission,
-    pub number: NumberSchema,
+    #[serde(flatten)]
+    pub value_type: ValueType,
+    ///What the value measures or controls, e.g. `"temperature"` or `"switch"`
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    ///How often the value is reported, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub period: Option<String>,
+    ///How much the value must change before it is reported
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<String>,
     pub state: Vec<State>,
     pub meta: Meta,
 }
 
 impl ValueSchema {
-    pub fn new(name: &str, permission: Permission, number: NumberSchema) -> Self {
-        Self::new_with_id(name, permission, number, Uuid::new_v4())
+    pub fn new(name: &str, permission: Permission, value_type: ValueType) -> Self {
+        Self::new_with_id(name, permission, value_type, Uuid::new_v4())
     }
-    pub fn new_with_id(name: &str, permission: Permission, number: NumberSchema, id: Uuid) -> Self {
+    pub fn new_with_id(
+        name: &str,
+        permission: Permission,
+        value_type: ValueType,
+        id: Uuid,
+    ) -> Self {
         let state = match permission {
             Permission::R => vec![State::new(StateType::Report)],
             Permission::W => vec![State::new(StateType::Control)],
@@ -67,7 +117,11 @@ impl ValueSchema {
         ValueSchema {
             name: String::from(name),
             permission,
-            number,
+            value_type,
+            type_name: None,
+            description: None,
+            period: None,
+            delta: None,
             state,
             meta: Meta::new_with_uuid(id, MetaType::Value),
         }
@@ -76,17 +130,17 @@ impl ValueSchema {
 
 impl Default for ValueSchema {
     fn default() -> Self {
-        ValueSchema::new("State", Permission::R, NumberSchema::default())
+        ValueSchema::new("State", Permission::R, ValueType::default())
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct State {
-    data: String,
+    pub data: String,
     #[serde(rename = "type")]
-    state_type: StateType,
-    timestamp: String,
-    meta: Meta,
+    pub state_type: StateType,
+    pub timestamp: String,
+    pub meta: Meta,
 }
 
 impl State {
@@ -102,9 +156,19 @@ impl State {
             meta: Meta::new_with_uuid(id, MetaType::State),
         }
     }
+
+    pub fn with_data(mut self, data: &str) -> Self {
+        self.data = String::from(data);
+        self
+    }
+
+    pub fn at(mut self, timestamp: DateTime<Utc>) -> Self {
+        self.timestamp = timestamp.format(DATE_FORMAT).to_string();
+        self
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum StateType {
     #[serde(rename = "Report")]
     Report,
@@ -112,12 +176,60 @@ pub enum StateType {
     Control,
 }
 
+///The kind of data a value holds. Wappsto expects exactly one of these on every value.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    Number(NumberSchema),
+    String(StringSchema),
+    Blob(BlobSchema),
+    Xml(XmlSchema),
+}
+
+impl ValueType {
+    ///Check that `data` is acceptable for a value of this type. XML is not validated against its
+    ///XSD.
+    pub fn validate(&self, data: &str) -> Result<(), ValidationError> {
+        match self {
+            ValueType::Number(number) => number.validate(data),
+            ValueType::String(string) => validate_length(data, string.max),
+            ValueType::Blob(blob) => validate_length(data, blob.max),
+            ValueType::Xml(_) => Ok(()),
+        }
+    }
+}
+
+impl Default for ValueType {
+    fn default() -> Self {
+        ValueType::Number(NumberSchema::default())
+    }
+}
+
+fn validate_length(data: &str, max: Option<usize>) -> Result<(), ValidationError> {
+    match max {
+        Some(max) if data.chars().count() > max => Err(ValidationError::TooLong(max)),
+        _ => Ok(()),
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NumberSchema {
     pub min: f64,
     pub max: f64,
     pub step: f64,
     pub unit: String,
+    ///The SI unit and the formula to convert to it, e.g. `"[K] = [°C] + 273.15"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub si_conversion: Option<String>,
+    ///Names for specific numbers, e.g. `"0"` to `"off"` and `"1"` to `"on"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mapping: Option<BTreeMap<String, String>>,
+    ///Whether the mapped numbers are ordered, e.g. low, medium, high
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ordered_mapping: Option<bool>,
+    ///What zero means for this value, e.g. `"off"` for a dimmer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meaningful_zero: Option<String>,
 }
 
 impl NumberSchema {
@@ -127,8 +239,63 @@ impl NumberSchema {
             max,
             step,
             unit: unit.to_string(),
+            si_conversion: None,
+            mapping: None,
+            ordered_mapping: None,
+            meaningful_zero: None,
         }
     }
+
+    pub fn si_conversion(mut self, si_conversion: &str) -> Self {
+        self.si_conversion = Some(String::from(si_conversion));
+        self
+    }
+
+    pub fn mapping(mut self, number: &str, meaning: &str) -> Self {
+        self.mapping
+            .get_or_insert_with(BTreeMap::new)
+            .insert(String::from(number), String::from(meaning));
+        self
+    }
+
+    pub fn ordered_mapping(mut self, ordered: bool) -> Self {
+        self.ordered_mapping = Some(ordered);
+        self
+    }
+
+    pub fn meaningful_zero(mut self, meaning: &str) -> Self {
+        self.meaningful_zero = Some(String::from(meaning));
+        self
+    }
+
+    ///Round `number` to the nearest step counted from the minimum
+    fn round(&self, number: f64) -> f64 {
+        if self.step <= 0f64 || !self.step.is_finite() || !number.is_finite() {
+            return number;
+        }
+        let rounded = self.min + ((number - self.min) / self.step).round() * self.step;
+        let decimals = self
+            .step
+            .to_string()
+            .split_once('.')
+            .map_or(0, |(_, d)| d.len());
+        format!("{:.*}", decimals, rounded)
+            .parse()
+            .unwrap_or(rounded)
+    }
+
+    fn validate(&self, data: &str) -> Result<(), ValidationError> {
+        let number = data
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|n| n.is_finite())
+            .ok_or_else(|| ValidationError::NotANumber(String::from(data)))?;
+        if number < self.min || number > self.max {
+            return Err(ValidationError::OutOfRange(number, self.min, self.max));
+        }
+        Ok(())
+    }
 }
 
 impl Default for NumberSchema {
@@ -137,6 +304,202 @@ impl Default for NumberSchema {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StringSchema {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+impl StringSchema {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: Some(max),
+            encoding: None,
+        }
+    }
+
+    pub fn encoding(mut self, encoding: &str) -> Self {
+        self.encoding = Some(String::from(encoding));
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BlobSchema {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+impl BlobSchema {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: Some(max),
+            encoding: None,
+        }
+    }
+
+    pub fn encoding(mut self, encoding: &str) -> Self {
+        self.encoding = Some(String::from(encoding));
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct XmlSchema {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xsd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+impl XmlSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn xsd(mut self, xsd: &str) -> Self {
+        self.xsd = Some(String::from(xsd));
+        self
+    }
+
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(String::from(namespace));
+        self
+    }
+}
+
+///Conversion from the data of a state, checked against the type of its value
+pub trait FromData: Sized {
+    fn from_data(data: &str, value_type: &ValueType) -> Result<Self, ValidationError>;
+}
+
+impl FromData for String {
+    fn from_data(data: &str, value_type: &ValueType) -> Result<Self, ValidationError> {
+        value_type.validate(data)?;
+        Ok(String::from(data))
+    }
+}
+
+impl FromData for f64 {
+    fn from_data(data: &str, value_type: &ValueType) -> Result<Self, ValidationError> {
+        value_type.validate(data)?;
+        data.trim()
+            .parse()
+            .map_err(|_| ValidationError::NotANumber(String::from(data)))
+    }
+}
+
+impl FromData for i64 {
+    fn from_data(data: &str, value_type: &ValueType) -> Result<Self, ValidationError> {
+        value_type.validate(data)?;
+        data.trim()
+            .parse()
+            .map_err(|_| ValidationError::NotANumber(String::from(data)))
+    }
+}
+
+///Accepts `"1"`, `"0"`, `"true"` and `"false"`
+impl FromData for bool {
+    fn from_data(data: &str, value_type: &ValueType) -> Result<Self, ValidationError> {
+        let flag = match data.trim() {
+            "1" | "true" => true,
+            "0" | "false" => false,
+            _ => return Err(ValidationError::NotABool(String::from(data))),
+        };
+        value_type.validate(if flag { "1" } else { "0" })?;
+        Ok(flag)
+    }
+}
+
+///Conversion of reported data to the data of a state, checked against the type of its value.
+///Numbers are rounded to the step of a number value.
+pub trait IntoData {
+    fn into_data(self, value_type: &ValueType) -> Result<String, ValidationError>;
+}
+
+impl IntoData for f64 {
+    fn into_data(self, value_type: &ValueType) -> Result<String, ValidationError> {
+        let data = match value_type {
+            ValueType::Number(number) => number.round(self).to_string(),
+            _ => self.to_string(),
+        };
+        value_type.validate(&data)?;
+        Ok(data)
+    }
+}
+
+impl IntoData for i64 {
+    fn into_data(self, value_type: &ValueType) -> Result<String, ValidationError> {
+        (self as f64).into_data(value_type)
+    }
+}
+
+///Reported as `"1"` or `"0"`
+impl IntoData for bool {
+    fn into_data(self, value_type: &ValueType) -> Result<String, ValidationError> {
+        let data = String::from(if self { "1" } else { "0" });
+        value_type.validate(&data)?;
+        Ok(data)
+    }
+}
+
+impl IntoData for &str {
+    fn into_data(self, value_type: &ValueType) -> Result<String, ValidationError> {
+        value_type.validate(self)?;
+        Ok(String::from(self))
+    }
+}
+
+impl IntoData for String {
+    fn into_data(self, value_type: &ValueType) -> Result<String, ValidationError> {
+        value_type.validate(&self)?;
+        Ok(self)
+    }
+}
+
+///Reported base64 encoded
+impl IntoData for &[u8] {
+    fn into_data(self, value_type: &ValueType) -> Result<String, ValidationError> {
+        STANDARD.encode(self).into_data(value_type)
+    }
+}
+
+impl IntoData for Vec<u8> {
+    fn into_data(self, value_type: &ValueType) -> Result<String, ValidationError> {
+        self.as_slice().into_data(value_type)
+    }
+}
+
+///Data that does not match the type of the value it is reported on
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    NotANumber(String),
+    NotABool(String),
+    ///The number, and the minimum and maximum it must be within
+    OutOfRange(f64, f64, f64),
+    ///The maximum length that was exceeded
+    TooLong(usize),
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotANumber(data) => write!(f, "Not a number: {}", data),
+            Self::NotABool(data) => write!(f, "Not a boolean: {}", data),
+            Self::OutOfRange(n, min, max) => {
+                write!(f, "{} is out of range [{}, {}]", n, min, max)
+            }
+            Self::TooLong(max) => write!(f, "Longer than the maximum of {}", max),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum Permission {
@@ -0,0 +1,139 @@
+mod value {
+    use serde_json::json;
+
+    use crate::schema::{
+        BlobSchema, IntoData, NumberSchema, Permission, StringSchema, ValidationError, ValueSchema,
+        ValueType, XmlSchema,
+    };
+
+    #[test]
+    fn should_serialize_value_type_under_its_own_key() {
+        let value = ValueSchema::new(
+            "test",
+            Permission::R,
+            ValueType::String(StringSchema::new(10).encoding("utf-8")),
+        );
+        let value = serde_json::to_value(&value).unwrap();
+        assert_eq!(json!({"max": 10, "encoding": "utf-8"}), value["string"]);
+        assert!(value.get("number").is_none());
+    }
+
+    #[test]
+    fn should_serialize_value_metadata() {
+        let mut value = ValueSchema::new("test", Permission::R, ValueType::default());
+        value.type_name = Some(String::from("switch"));
+        value.period = Some(String::from("60"));
+        let json = serde_json::to_value(&value).unwrap();
+        assert_eq!("switch", json["type"]);
+        assert_eq!("60", json["period"]);
+        assert!(json.get("description").is_none());
+
+        let value: ValueSchema = serde_json::from_value(json).unwrap();
+        assert_eq!(Some(String::from("switch")), value.type_name);
+        assert_eq!(None, value.delta);
+    }
+
+    #[test]
+    fn should_deserialize_each_value_type() {
+        let types = vec![
+            ValueType::Number(NumberSchema::default()),
+            ValueType::String(StringSchema::new(10)),
+            ValueType::Blob(BlobSchema::new(10).encoding("base64")),
+            ValueType::Xml(XmlSchema::new().namespace("test")),
+        ];
+        for value_type in types {
+            let value = ValueSchema::new("test", Permission::R, value_type.clone());
+            let json = serde_json::to_string(&value).unwrap();
+            let value: ValueSchema = serde_json::from_str(&json).unwrap();
+            assert_eq!(
+                serde_json::to_value(value_type).unwrap(),
+                serde_json::to_value(value.value_type).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn should_serialize_number_details() {
+        let number = NumberSchema::new(-20f64, 50f64, 0.5, "°C")
+            .si_conversion("[K] = [°C] + 273.15")
+            .mapping("0", "freezing")
+            .ordered_mapping(false)
+            .meaningful_zero("freezing");
+        assert_eq!(
+            json!({
+                "min": -20.0,
+                "max": 50.0,
+                "step": 0.5,
+                "unit": "°C",
+                "si_conversion": "[K] = [°C] + 273.15",
+                "mapping": {"0": "freezing"},
+                "ordered_mapping": false,
+                "meaningful_zero": "freezing"
+            }),
+            serde_json::to_value(number).unwrap()
+        );
+    }
+
+    #[test]
+    fn should_deserialize_numbers_without_details() {
+        let number: NumberSchema =
+            serde_json::from_value(json!({"min": 0, "max": 1, "step": 1, "unit": ""})).unwrap();
+        assert!(number.si_conversion.is_none());
+        assert!(number.mapping.is_none());
+    }
+
+    #[test]
+    fn should_validate_numbers_against_range() {
+        let number = ValueType::Number(NumberSchema::new(0f64, 10f64, 1f64, ""));
+        assert!(number.validate("5").is_ok());
+        assert_eq!(
+            Err(ValidationError::OutOfRange(11f64, 0f64, 10f64)),
+            number.validate("11")
+        );
+        assert_eq!(
+            Err(ValidationError::NotANumber(String::from("NaN"))),
+            number.validate("NaN")
+        );
+    }
+
+    #[test]
+    fn should_validate_length_of_strings_and_blobs() {
+        assert!(ValueType::String(StringSchema::new(3))
+            .validate("abc")
+            .is_ok());
+        assert_eq!(
+            Err(ValidationError::TooLong(3)),
+            ValueType::Blob(BlobSchema::new(3)).validate("abcd")
+        );
+    }
+
+    #[test]
+    fn should_round_reported_numbers_to_step() {
+        let number = ValueType::Number(NumberSchema::new(0f64, 30f64, 0.1, "°C"));
+        assert_eq!(Ok(String::from("21.5")), 21.53.into_data(&number));
+        let number = ValueType::Number(NumberSchema::new(1f64, 9f64, 2f64, ""));
+        assert_eq!(Ok(String::from("5")), 5.9.into_data(&number));
+        assert_eq!(Ok(String::from("7")), 6i64.into_data(&number));
+    }
+
+    #[test]
+    fn should_reject_invalid_reported_numbers() {
+        let number = ValueType::Number(NumberSchema::new(0f64, 10f64, 1f64, ""));
+        assert!(f64::NAN.into_data(&number).is_err());
+        assert_eq!(
+            Err(ValidationError::OutOfRange(11f64, 0f64, 10f64)),
+            10.6.into_data(&number)
+        );
+    }
+
+    #[test]
+    fn should_convert_reported_booleans_and_blobs() {
+        assert_eq!(Ok(String::from("1")), true.into_data(&ValueType::default()));
+        let blob = ValueType::Blob(BlobSchema::new(8));
+        assert_eq!(Ok(String::from("AAEC")), vec![0u8, 1, 2].into_data(&blob));
+        assert_eq!(
+            Err(ValidationError::TooLong(8)),
+            [0u8; 7].as_slice().into_data(&blob)
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{error::Error, path::Path, sync::Mutex};
+use uuid::Uuid;
+
+use crate::{
+    certs::Certs,
+    fs_store::{CorruptSchema, Store},
+    schema::Schema,
+};
+
+///Keeps the certificates and network schema in a SQLite database, for devices that already
+///keep their state there
+pub struct SqliteStore {
+    db: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    ///Open the database at the given path, creating it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, Box<dyn Error>> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(db: Connection) -> Result<Self, Box<dyn Error>> {
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS certs (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                ca TEXT NOT NULL,
+                certificate TEXT NOT NULL,
+                private_key TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS network_schema (
+                id TEXT PRIMARY KEY,
+                schema TEXT NOT NULL
+            );",
+        )?;
+        Ok(Self { db: Mutex::new(db) })
+    }
+
+    pub fn save_certs(&self, certs: Certs) -> Result<(), Box<dyn Error>> {
+        self.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO certs (id, ca, certificate, private_key) VALUES (0, ?1, ?2, ?3)",
+            params![
+                String::from_utf8(certs.ca.to_pem()?)?,
+                String::from_utf8(certs.certificate.to_pem()?)?,
+                String::from_utf8(certs.private_key.private_key_to_pem_pkcs8()?)?,
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+impl Store for SqliteStore {
+    fn load_certs(&self) -> Result<Certs, Box<dyn Error>> {
+        let (ca, certificate, private_key): (String, String, String) =
+            self.db.lock().unwrap().query_row(
+                "SELECT ca, certificate, private_key FROM certs WHERE id = 0",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?;
+        Certs::new(&ca, &certificate, &private_key)
+    }
+
+    fn save_schema(&self, schema: Schema) -> Result<(), Box<dyn Error>> {
+        self.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO network_schema (id, schema) VALUES (?1, ?2)",
+            params![schema.meta.id.to_string(), serde_json::to_string(&schema)?],
+        )?;
+        Ok(())
+    }
+
+    fn load_schema(&self, id: Uuid) -> Result<Option<Schema>, Box<dyn Error>> {
+        let contents: Option<String> = self
+            .db
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT schema FROM network_schema WHERE id = ?1",
+                params![id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match contents {
+            Some(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(|source| CorruptSchema { id, source }.into()),
+            None => Ok(None),
+        }
+    }
+}
@@ -1,28 +1,59 @@
-use std::io::{self, Read, Write};
+use crate::communication::Close;
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
 
+#[derive(Clone, Default)]
 pub struct StreamMock {
-    pub in_buffer: String,
-    pub out_buffer: String,
+    pub in_buffer: Arc<Mutex<String>>,
+    pub out_buffer: Arc<Mutex<String>>,
+    pub closed: Arc<AtomicBool>,
+    pub shut_down: Arc<AtomicBool>,
 }
 
 impl StreamMock {
     pub fn new() -> Self {
-        Self {
-            in_buffer: String::new(),
-            out_buffer: String::new(),
-        }
+        Self::default()
+    }
+
+    pub fn receive(&self, message: &str) {
+        self.in_buffer.lock().unwrap().push_str(message)
     }
 
-    pub fn receive(&mut self, message: &str) {
-        self.in_buffer.push_str(message)
+    pub fn disconnect(&self) {
+        self.closed.store(true, Ordering::SeqCst)
+    }
+
+    pub fn sent(&self) -> String {
+        self.out_buffer.lock().unwrap().clone()
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+}
+
+impl Close for StreamMock {
+    fn close(&mut self) -> io::Result<()> {
+        self.shut_down.store(true, Ordering::SeqCst);
+        Ok(())
     }
 }
 
 impl Read for StreamMock {
-    fn read(&mut self, mut buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.in_buffer.len() > 0 {
-            buf.write_all(self.in_buffer.as_bytes())?;
-            Ok(self.in_buffer.len())
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut in_buffer = self.in_buffer.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            Ok(0)
+        } else if !in_buffer.is_empty() {
+            let bytes = in_buffer.len().min(buf.len());
+            buf[..bytes].copy_from_slice(&in_buffer.as_bytes()[..bytes]);
+            in_buffer.drain(..bytes);
+            Ok(bytes)
         } else {
             Err(io::Error::from(io::ErrorKind::WouldBlock))
         }
@@ -32,7 +63,9 @@ impl Read for StreamMock {
 impl Write for StreamMock {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.out_buffer
-            .push_str(&buf.iter().map(|c| *c as char).collect::<String>());
+            .lock()
+            .unwrap()
+            .push_str(&String::from_utf8_lossy(buf));
         Ok(buf.len())
     }
 
@@ -0,0 +1,55 @@
+use chrono::Utc;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use uuid::Uuid;
+
+use crate::{
+    communication::ErrorHandler,
+    connection::Backoff,
+    rpc::{RpcData, RpcMethod, RpcRequest, RpcStateData, RpcType},
+    schema::{Meta, MetaType},
+};
+
+pub fn ignore_errors() -> ErrorHandler {
+    Arc::new(Mutex::new(Box::new(|_| {})))
+}
+
+pub fn fast_backoff() -> Backoff {
+    Backoff {
+        initial: Duration::from_millis(1),
+        ..Backoff::default()
+    }
+}
+
+///A control of the state `id` from Wappsto
+pub fn control_state_rpc(data: &str, id: Uuid) -> String {
+    serde_json::to_string(
+        &RpcRequest::builder()
+            .method(RpcMethod::Put)
+            .data(RpcData::Data(RpcStateData::new(
+                data,
+                Utc::now(),
+                Meta::new_with_uuid(id, MetaType::State),
+            )))
+            .create(),
+    )
+    .unwrap()
+}
+
+///A report of the state `id` to Wappsto
+pub fn report_rpc(data: &str, id: Uuid) -> String {
+    serde_json::to_string(
+        &RpcRequest::builder()
+            .method(RpcMethod::Put)
+            .on_type(RpcType::State)
+            .data(RpcData::Data(RpcStateData::new(
+                data,
+                Utc::now(),
+                Meta::new_with_uuid(id, MetaType::State),
+            )))
+            .create(),
+    )
+    .unwrap()
+}
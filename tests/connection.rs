@@ -1,8 +1,12 @@
-use dotenv;
-use std::{collections::HashMap, env};
+use std::{
+    env,
+    sync::{Arc, Mutex},
+};
 use wappsto_iot_rs::connection::Connect;
 use wappsto_iot_rs::create_network::{RequestBuilder, WappstoServers};
-use wappsto_iot_rs::{certs::Certs, connection::Connection};
+use wappsto_iot_rs::{
+    certs::Certs, communication::Pending, connection::Connection, queue::MessageQueue,
+};
 
 mod support {
     pub(crate) mod aw;
@@ -28,6 +32,12 @@ fn connects_to_wappsto() {
         certs.unwrap(),
         wappsto_iot_rs::connection::WappstoServers::QA,
     )
-    .start(HashMap::new())
+    .start(
+        Arc::default(),
+        Pending::default(),
+        Arc::new(Mutex::new(Box::new(|_| {}))),
+        Arc::new(Mutex::new(MessageQueue::default())),
+        Box::new(|| None),
+    )
     .is_ok());
 }
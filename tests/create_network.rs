@@ -1,4 +1,3 @@
-use dotenv;
 use std::env;
 use wappsto_iot_rs::create_network::*;
 
@@ -4,26 +4,27 @@ mod support {
     pub(crate) mod rest;
 }
 use support::rest::rest::{create_network, credentials, RestServer, RestSession};
-use wappsto_iot_rs::{connection::WappstoServers, network::*};
+use wappsto_iot_rs::{
+    connection::WappstoServers,
+    network::*,
+    schema::{NumberSchema, ValueType},
+};
 
 #[test]
 fn should_report_state_change_to_wappsto() {
     create_network().expect("Failed to create network");
     let network: Network = Network::new_at(WappstoServers::QA, "test").unwrap();
     let device = network.create_device("thing");
-    let value = device.create_value("value", ValuePermission::R);
-    let report_id = value
-        .inner
-        .lock()
-        .unwrap()
-        .report
-        .as_ref()
-        .unwrap()
-        .id
-        .clone();
+    let value = device
+        .value_builder("value")
+        .permission(ValuePermission::R)
+        .value_type(ValueType::Number(NumberSchema::new(0f64, 10f64, 1f64, "")))
+        .create()
+        .unwrap();
+    let report_id = value.inner.lock().unwrap().report.as_ref().unwrap().id;
     network.start().expect("Failed to start network");
     let (username, password) = credentials();
-    value.report("5");
+    value.report("5").unwrap();
     sleep(Duration::from_secs(1));
     let report_value = RestSession::new(&username, &password, RestServer::Qa)
         .report(report_id)
@@ -5,4 +5,5 @@ macro_rules! aw {
     };
 }
 
+#[allow(unused_imports)]
 pub use aw;
@@ -1,4 +1,4 @@
-#[allow(dead_code)]
+#[allow(dead_code, clippy::module_inception)]
 pub mod rest {
     use reqwest::{
         blocking::{Client, ClientBuilder},